@@ -0,0 +1,11 @@
+CREATE TABLE message (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Milliseconds since the Unix epoch (UTC) when the server received the message
+    created_at INTEGER NOT NULL,
+    -- 'text' or 'system'
+    kind TEXT NOT NULL,
+    -- NULL for system messages
+    username TEXT,
+    content TEXT NOT NULL,
+    signature TEXT
+);
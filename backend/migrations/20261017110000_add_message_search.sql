@@ -0,0 +1,24 @@
+-- Full-text index over the text messages in the history.
+-- This is an external-content table, so the text itself is only stored in `message`.
+CREATE VIRTUAL TABLE message_fts USING fts5(
+    username,
+    content,
+    content='message',
+    content_rowid='id'
+);
+
+INSERT INTO message_fts (rowid, username, content)
+    SELECT id, username, content FROM message WHERE kind = 'text';
+
+CREATE TRIGGER message_fts_after_insert AFTER INSERT ON message WHEN new.kind = 'text' BEGIN
+    INSERT INTO message_fts (rowid, username, content) VALUES (new.id, new.username, new.content);
+END;
+
+CREATE TRIGGER message_fts_after_delete AFTER DELETE ON message WHEN old.kind = 'text' BEGIN
+    INSERT INTO message_fts (message_fts, rowid, username, content) VALUES ('delete', old.id, old.username, old.content);
+END;
+
+CREATE TRIGGER message_fts_after_update AFTER UPDATE ON message WHEN old.kind = 'text' BEGIN
+    INSERT INTO message_fts (message_fts, rowid, username, content) VALUES ('delete', old.id, old.username, old.content);
+    INSERT INTO message_fts (rowid, username, content) VALUES (new.id, new.username, new.content);
+END;
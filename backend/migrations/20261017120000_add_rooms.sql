@@ -0,0 +1,12 @@
+CREATE TABLE room (
+    name TEXT NOT NULL PRIMARY KEY,
+    -- Milliseconds since the Unix epoch (UTC)
+    created_at INTEGER NOT NULL
+);
+
+INSERT INTO room (name, created_at) VALUES ('general', CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER));
+
+-- NULL for system messages that are shown in every room
+ALTER TABLE message ADD COLUMN room TEXT;
+UPDATE message SET room = 'general' WHERE kind = 'text';
+CREATE INDEX message_room ON message (room, id);
@@ -0,0 +1,7 @@
+-- For direct messages, the user the message was sent to; NULL otherwise
+ALTER TABLE message ADD COLUMN recipient TEXT;
+CREATE INDEX message_direct ON message (username, recipient, id) WHERE kind = 'direct';
+
+-- The user who registered this subscription, if they proved who they are.
+-- Direct messages are only pushed to the recipient's subscriptions.
+ALTER TABLE subscription ADD COLUMN username TEXT;
@@ -0,0 +1,2 @@
+-- Whether the content of a direct message is end-to-end encrypted, so the server can't read it
+ALTER TABLE message ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- Sequence number of every stored message, shared with the messages that are only sent to one client.
+-- Existing messages were created in ID order, so their IDs work as sequence numbers.
+ALTER TABLE message ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+UPDATE message SET seq = id;
+CREATE UNIQUE INDEX message_seq ON message (seq);
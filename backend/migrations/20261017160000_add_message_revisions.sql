@@ -0,0 +1,13 @@
+-- When the message was last edited, or NULL if it never was
+ALTER TABLE message ADD COLUMN edited_at INTEGER;
+
+-- Earlier versions of edited messages
+CREATE TABLE message_revision (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL REFERENCES message(id),
+    -- When this version was written: when the message was sent, or when the edit that produced it was made
+    created_at INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    signature TEXT
+);
+CREATE INDEX message_revision_message ON message_revision (message_id, id);
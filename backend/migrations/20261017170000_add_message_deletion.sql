@@ -0,0 +1,5 @@
+-- When the message was deleted. Deleted messages are kept as tombstones without their content.
+ALTER TABLE message ADD COLUMN deleted_at INTEGER;
+
+-- Moderators can delete anyone's messages
+ALTER TABLE user ADD COLUMN is_moderator BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,8 @@
+-- Every user can react to a message with every emoji once
+CREATE TABLE reaction (
+    message_id INTEGER NOT NULL REFERENCES message(id),
+    username TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (message_id, username, emoji)
+);
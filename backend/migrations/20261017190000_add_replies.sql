@@ -0,0 +1,3 @@
+-- For replies, the message that they are a reply to
+ALTER TABLE message ADD COLUMN reply_to INTEGER REFERENCES message(id);
+CREATE INDEX message_reply_to ON message (reply_to, id) WHERE reply_to IS NOT NULL;
@@ -0,0 +1,19 @@
+-- Usernames have to be unique for subscriptions to refer to them; registration already makes sure of this
+DELETE FROM user WHERE rowid NOT IN (SELECT MIN(rowid) FROM user GROUP BY name);
+CREATE UNIQUE INDEX user_name ON user (name);
+
+-- Every subscription now belongs to a user, so that notifications can be targeted at people.
+-- Subscriptions that nobody proved ownership of can't be kept; their owners need to subscribe again.
+-- An endpoint can only be registered once: registering it again replaces the old keys and owner.
+CREATE TABLE subscription_new (
+    endpoint TEXT NOT NULL UNIQUE,
+    p256dh TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    username TEXT NOT NULL REFERENCES user(name) ON DELETE CASCADE
+);
+INSERT INTO subscription_new (endpoint, p256dh, auth, username)
+    SELECT endpoint, p256dh, auth, username FROM subscription
+    WHERE rowid IN (SELECT MAX(rowid) FROM subscription WHERE username IN (SELECT name FROM user) GROUP BY endpoint);
+DROP TABLE subscription;
+ALTER TABLE subscription_new RENAME TO subscription;
+CREATE INDEX subscription_username ON subscription (username);
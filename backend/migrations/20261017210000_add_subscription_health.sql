@@ -0,0 +1,7 @@
+-- How well pushing to each subscription has been going, to find unhealthy ones
+ALTER TABLE subscription ADD COLUMN failure_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE subscription ADD COLUMN consecutive_failures INTEGER NOT NULL DEFAULT 0;
+-- Milliseconds since the Unix epoch
+ALTER TABLE subscription ADD COLUMN last_success_at INTEGER;
+ALTER TABLE subscription ADD COLUMN last_failure_at INTEGER;
+ALTER TABLE subscription ADD COLUMN last_error TEXT;
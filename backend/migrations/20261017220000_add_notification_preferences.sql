@@ -0,0 +1,19 @@
+-- What each user wants push notifications about. Users without a row get the defaults.
+CREATE TABLE notification_preferences (
+    username TEXT PRIMARY KEY NOT NULL REFERENCES user(name) ON DELETE CASCADE,
+    -- 'all' or 'mentions_and_direct'
+    level TEXT NOT NULL DEFAULT 'all',
+    -- Minutes after midnight in the user's timezone; both NULL if there are no quiet hours
+    quiet_start INTEGER,
+    quiet_end INTEGER,
+    -- Minutes ahead of UTC
+    utc_offset INTEGER NOT NULL DEFAULT 0,
+    skip_while_connected BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+-- Rooms that users don't want push notifications about
+CREATE TABLE muted_room (
+    username TEXT NOT NULL REFERENCES user(name) ON DELETE CASCADE,
+    room TEXT NOT NULL,
+    PRIMARY KEY (username, room)
+);
@@ -0,0 +1,6 @@
+-- How far the server may have handed out sequence numbers, including to messages that are never stored.
+-- There is only ever one row.
+CREATE TABLE sequence_reservation (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+    reserved_until INTEGER NOT NULL
+);
@@ -0,0 +1,4 @@
+-- The sequence number of the last edit, deletion or reaction of a message,
+-- so that clients resuming after a reconnect can be sent the changes they missed.
+ALTER TABLE message ADD COLUMN changed_seq INTEGER;
+CREATE INDEX message_changed_seq ON message (changed_seq);
@@ -0,0 +1,197 @@
+//! Collapsing bursts of pushes about the same conversation into one.
+//!
+//! The first message in a burst is pushed right away. Messages that follow within the coalescing window
+//! aren't pushed by themselves: instead, when the window ends, one summary of the whole burst is pushed.
+//! All pushes about a conversation share a Web Push topic, so a newer summary replaces an older one
+//! that the push service hasn't delivered yet. That is fine, because every summary covers the whole burst.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::DEFAULT_ROOM;
+use sqlx::SqlitePool;
+use web_push::PartialVapidSignatureBuilder;
+
+use crate::{
+    notification::{send_with_retries, Notification, Subscription},
+    push_client::PushClient,
+    push_config::PushParams,
+};
+
+/// A burst of messages ends once nothing new has been said in the conversation for this long.
+const COALESCING_WINDOW: Duration = Duration::from_secs(10);
+/// How many senders are named in a summary, before the rest are just counted.
+const MAX_NAMED_SENDERS: usize = 3;
+
+/// Which conversation a push is about. Only pushes about the same conversation are coalesced.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Room(String),
+    /// Direct messages from this user.
+    Direct(String),
+}
+
+impl Conversation {
+    /// The notification tag, so that the service worker shows one notification per conversation.
+    pub fn tag(&self) -> String {
+        match self {
+            Conversation::Room(room) => format!("room:{room}"),
+            Conversation::Direct(from) => format!("direct:{from}"),
+        }
+    }
+
+    /// Topics can be at most 32 characters from the URL-safe base64 alphabet, so the tag is hashed.
+    /// The hash must not change between builds, or pushes sent before and after a restart wouldn't replace each other.
+    fn topic(&self) -> String {
+        // 64-bit FNV-1a
+        let hash = self.tag().bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{hash:016x}")
+    }
+}
+
+/// Messages about one conversation that were pushed to one subscription in quick succession.
+struct Burst {
+    sub: Subscription,
+    count: u32,
+    /// Everyone who sent a message in the burst, in the order they first did.
+    senders: Vec<String>,
+    /// The newest message, which the summary shows and links to.
+    latest: Notification,
+    params: PushParams,
+    /// Whether there are messages that haven't been pushed, either by themselves or in a summary.
+    unsent: bool,
+}
+
+impl Burst {
+    fn summary(&self, conversation: &Conversation) -> Notification {
+        let mut senders = self.senders[..self.senders.len().min(MAX_NAMED_SENDERS)].join(", ");
+        if self.senders.len() > MAX_NAMED_SENDERS {
+            senders.push_str(&format!(" and {} others", self.senders.len() - MAX_NAMED_SENDERS));
+        }
+        let title = match conversation {
+            Conversation::Room(room) if room == DEFAULT_ROOM => {
+                format!("{} new messages from {senders}", self.count)
+            }
+            Conversation::Room(room) => {
+                format!("{} new messages in #{room} from {senders}", self.count)
+            }
+            Conversation::Direct(from) => format!("{} direct messages from {from}", self.count),
+        };
+        Notification {
+            title,
+            body: self.latest.body.clone(),
+            always_show: false,
+            tag: conversation.tag(),
+            link: self.latest.link.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Coalescer {
+    pool: SqlitePool,
+    signer: PartialVapidSignatureBuilder,
+    client: PushClient,
+    bursts: Arc<Mutex<HashMap<(String, Conversation), Burst>>>,
+}
+
+impl Coalescer {
+    pub fn new(pool: SqlitePool, signer: PartialVapidSignatureBuilder, client: PushClient) -> Self {
+        Coalescer {
+            pool,
+            signer,
+            client,
+            bursts: Default::default(),
+        }
+    }
+
+    /// Push a message to a subscription, unless it is part of a burst that will be summarized later.
+    pub fn push(
+        &self,
+        sub: Subscription,
+        conversation: Conversation,
+        sender: &str,
+        notification: Notification,
+        params: PushParams,
+    ) {
+        let key = (sub.endpoint.clone(), conversation.clone());
+        {
+            let mut bursts = self.bursts.lock().unwrap();
+            if let Some(burst) = bursts.get_mut(&key) {
+                burst.count += 1;
+                if !burst.senders.iter().any(|seen| seen == sender) {
+                    burst.senders.push(sender.to_string());
+                }
+                burst.latest = notification;
+                burst.unsent = true;
+                return;
+            }
+            bursts.insert(
+                key.clone(),
+                Burst {
+                    sub: sub.clone(),
+                    count: 1,
+                    senders: vec![sender.to_string()],
+                    latest: notification.clone(),
+                    params: params.clone(),
+                    unsent: false,
+                },
+            );
+        }
+        tokio::spawn(send_with_retries(
+            self.pool.clone(),
+            sub,
+            self.signer.clone(),
+            self.client.clone(),
+            notification,
+            params,
+            Some(conversation.topic()),
+        ));
+        tokio::spawn(self.clone().summarize_burst(key));
+    }
+
+    /// Every coalescing window, push a summary of the burst if anything new was said in it,
+    /// or end the burst if nothing was.
+    async fn summarize_burst(self, key: (String, Conversation)) {
+        loop {
+            tokio::time::sleep(COALESCING_WINDOW).await;
+            let (sub, summary, params) = {
+                let mut bursts = self.bursts.lock().unwrap();
+                let Some(burst) = bursts.get_mut(&key) else {
+                    return;
+                };
+                if !burst.unsent {
+                    bursts.remove(&key);
+                    return;
+                }
+                burst.unsent = false;
+                (burst.sub.clone(), burst.summary(&key.1), burst.params.clone())
+            };
+            tokio::spawn(send_with_retries(
+                self.pool.clone(),
+                sub,
+                self.signer.clone(),
+                self.client.clone(),
+                summary,
+                params,
+                Some(key.1.topic()),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_are_stable() {
+        assert_eq!(Conversation::Room("general".to_string()).topic(), "e26b9a568c6032a8");
+        assert_eq!(Conversation::Direct("alice".to_string()).topic(), "8d3f4ffa66fd3282");
+    }
+}
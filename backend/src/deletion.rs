@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Response,
+    Json,
+};
+use common::{ChatMessage, MessageId};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    history,
+    users::{self, SignatureCheck},
+    AppState,
+};
+
+/// Whether a user may delete a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletePermission {
+    Allowed,
+    /// There is no text or direct message with this ID (or it was already deleted).
+    NotFound,
+    /// The user is neither the author of the message nor a moderator.
+    Forbidden,
+}
+
+/// Check whether `username` may delete the message: they must be its author or a moderator.
+/// This doesn't check that the request actually came from `username`; callers must do that first.
+pub async fn check_delete_permission(
+    pool: &SqlitePool,
+    id: MessageId,
+    username: &str,
+) -> anyhow::Result<DeletePermission> {
+    let author = match history::fetch_message(pool, id).await?.map(|msg| msg.message) {
+        Some(ChatMessage::TextMessage { username, .. }) => username,
+        Some(ChatMessage::DirectMessage { from, .. }) => from,
+        _ => return Ok(DeletePermission::NotFound),
+    };
+    if author == username || users::is_moderator(pool, username).await? {
+        Ok(DeletePermission::Allowed)
+    } else {
+        Ok(DeletePermission::Forbidden)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    /// The user asking for the deletion.
+    username: String,
+    /// Signature over `signing::delete_message_payload(id)` by that user.
+    signature: Option<String>,
+}
+
+pub async fn delete_message(
+    State(appstate): State<AppState>,
+    Path(id): Path<MessageId>,
+    Json(request): Json<DeleteRequest>,
+) -> Response<String> {
+    async fn inner_delete_message(
+        appstate: AppState,
+        id: MessageId,
+        request: DeleteRequest,
+    ) -> anyhow::Result<Response<String>> {
+        let pool = &appstate.pool;
+        let username = &request.username;
+        let payload = common::signing::delete_message_payload(id);
+        let check =
+            users::check_signature(pool, username, &payload, request.signature.as_deref()).await?;
+        if check != SignatureCheck::Valid {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(format!("could not prove that this request came from {username}"))
+                .unwrap());
+        }
+        match check_delete_permission(pool, id, username).await? {
+            DeletePermission::NotFound => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("no message with this ID".to_string())
+                .unwrap()),
+            DeletePermission::Forbidden => Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body("only the author or a moderator can delete this message".to_string())
+                .unwrap()),
+            DeletePermission::Allowed => {
+                appstate
+                    .message_manager_tx
+                    .send(ChatMessage::DeleteMessage { id })
+                    .await?;
+                Ok(Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body("message will be deleted".to_string())
+                    .unwrap())
+            }
+        }
+    }
+
+    match inner_delete_message(appstate, id, request).await {
+        Ok(res) => res,
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+    }
+}
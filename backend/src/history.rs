@@ -0,0 +1,770 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use common::{
+    ChatMessage, HistoryPage, MessageId, MessageRevision, ReactionSummary, Seq, ServerMessage,
+    Thread, DEFAULT_ROOM,
+};
+use serde::Deserialize;
+use sqlx::{query, query_as, SqlitePool};
+use tokio::sync::Notify;
+
+use crate::AppState;
+
+/// Milliseconds since the Unix epoch, which is how timestamps are stored in the database.
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970?")
+        .as_millis() as i64
+}
+
+/// The sequence number that the next emitted message gets.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+/// Sequence numbers below this are recorded in the database as possibly handed out.
+static RESERVED_SEQ: AtomicU64 = AtomicU64::new(1);
+/// Woken up when it's time to reserve more sequence numbers.
+static RESERVE_MORE_SEQS: Notify = Notify::const_new();
+
+/// How many sequence numbers are reserved at a time.
+/// More are reserved when half of these are used, so this is how far a burst of messages can get ahead of the database.
+const SEQ_RESERVATION_BLOCK: Seq = 10_000;
+
+/// Record that sequence numbers up to `until` may be handed out.
+async fn reserve_sequence(pool: &SqlitePool, until: Seq) -> anyhow::Result<()> {
+    let until = until as i64;
+    query!(
+        "INSERT INTO sequence_reservation (id, reserved_until) VALUES (0, ?)
+        ON CONFLICT (id) DO UPDATE SET reserved_until = MAX(reserved_until, excluded.reserved_until)",
+        until
+    )
+    .execute(pool)
+    .await?;
+    RESERVED_SEQ.fetch_max(until as Seq, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Continue the sequence numbers from where the last run left off, so that they keep increasing across restarts.
+///
+/// Messages that aren't stored (like notices, edits or reactions) also get sequence numbers,
+/// so numbers are reserved in the database in blocks before they are handed out, and the next run starts after the reservation.
+/// This must run before any message is emitted.
+pub async fn init_sequence(pool: &SqlitePool) -> anyhow::Result<()> {
+    let last_seq = query!(
+        r#"SELECT MAX(
+            (SELECT COALESCE(MAX(seq), 0) FROM message),
+            (SELECT COALESCE(MAX(reserved_until), 0) FROM sequence_reservation)
+        ) AS "last_seq!: i64""#
+    )
+    .fetch_one(pool)
+    .await?
+    .last_seq;
+    let next_seq = last_seq as Seq + 1;
+    reserve_sequence(pool, next_seq + SEQ_RESERVATION_BLOCK).await?;
+    NEXT_SEQ.store(next_seq, Ordering::SeqCst);
+
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        loop {
+            RESERVE_MORE_SEQS.notified().await;
+            let until = NEXT_SEQ.load(Ordering::SeqCst) + SEQ_RESERVATION_BLOCK;
+            if let Err(why) = reserve_sequence(&pool, until).await {
+                eprintln!("Error reserving sequence numbers: {why}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Take the next sequence number.
+pub fn next_seq() -> Seq {
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::SeqCst);
+    if seq + SEQ_RESERVATION_BLOCK / 2 >= RESERVED_SEQ.load(Ordering::SeqCst) {
+        RESERVE_MORE_SEQS.notify_one();
+    }
+    seq
+}
+
+/// The last sequence number that was handed out.
+pub fn last_seq() -> Seq {
+    NEXT_SEQ.load(Ordering::SeqCst) - 1
+}
+
+/// Wrap a message that is only meant for one client and is not stored, giving it a sequence number and timestamp.
+pub fn ephemeral(message: ChatMessage) -> ServerMessage {
+    ServerMessage::ephemeral(next_seq(), now_millis(), message)
+}
+
+/// How many messages a client gets when it connects without saying what it has already seen.
+pub const DEFAULT_BACKFILL: i64 = 50;
+/// The most messages that are ever replayed to a connecting client.
+pub const MAX_BACKFILL: i64 = 1000;
+
+/// How many messages are in a page of `GET /messages` if the client doesn't say.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+/// The most messages that `GET /messages` will return at once.
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+/// Save a message to the history with the given sequence number and timestamp, returning its ID.
+///
+/// Returns `Ok(None)` for message types that are not part of the chat history.
+pub async fn store_message(
+    pool: &SqlitePool,
+    seq: Seq,
+    created_at: i64,
+    message: &ChatMessage,
+) -> anyhow::Result<Option<MessageId>> {
+    let seq = seq as i64;
+    let id = match message {
+        ChatMessage::TextMessage {
+            room,
+            username,
+            content,
+            signature,
+            reply_to,
+        } => query!(
+            "INSERT INTO message (seq, created_at, kind, room, username, content, signature, reply_to) VALUES (?, ?, 'text', ?, ?, ?, ?, ?)",
+            seq,
+            created_at,
+            room,
+            username,
+            content,
+            signature,
+            reply_to
+        )
+        .execute(pool)
+        .await?
+        .last_insert_rowid(),
+        ChatMessage::SystemMessage { room, content } => query!(
+            "INSERT INTO message (seq, created_at, kind, room, content) VALUES (?, ?, 'system', ?, ?)",
+            seq,
+            created_at,
+            room,
+            content
+        )
+        .execute(pool)
+        .await?
+        .last_insert_rowid(),
+        ChatMessage::DirectMessage {
+            from,
+            to,
+            content,
+            signature,
+            encrypted,
+        } => query!(
+            "INSERT INTO message (seq, created_at, kind, username, recipient, content, signature, encrypted) VALUES (?, ?, 'direct', ?, ?, ?, ?, ?)",
+            seq,
+            created_at,
+            from,
+            to,
+            content,
+            signature,
+            encrypted
+        )
+        .execute(pool)
+        .await?
+        .last_insert_rowid(),
+        _ => return Ok(None),
+    };
+    Ok(Some(id))
+}
+
+/// A row of the `message` table.
+pub struct MessageRow {
+    pub id: MessageId,
+    pub seq: i64,
+    pub created_at: i64,
+    pub edited_at: Option<i64>,
+    pub kind: String,
+    pub room: Option<String>,
+    pub username: Option<String>,
+    pub recipient: Option<String>,
+    pub content: String,
+    pub signature: Option<String>,
+    pub encrypted: bool,
+    pub reply_to: Option<MessageId>,
+}
+
+impl From<MessageRow> for ServerMessage {
+    fn from(row: MessageRow) -> Self {
+        let message = match row.kind.as_str() {
+            "text" => ChatMessage::TextMessage {
+                room: row.room.unwrap_or_else(|| DEFAULT_ROOM.to_string()),
+                username: row.username.unwrap_or_default(),
+                content: row.content,
+                signature: row.signature,
+                reply_to: row.reply_to,
+            },
+            "direct" => ChatMessage::DirectMessage {
+                from: row.username.unwrap_or_default(),
+                to: row.recipient.unwrap_or_default(),
+                content: row.content,
+                signature: row.signature,
+                encrypted: row.encrypted,
+            },
+            _ => ChatMessage::SystemMessage {
+                room: row.room,
+                content: row.content,
+            },
+        };
+        ServerMessage {
+            id: Some(row.id),
+            seq: row.seq as Seq,
+            timestamp: row.created_at,
+            edited_at: row.edited_at,
+            reactions: Vec::new(),
+            reply_count: 0,
+            message,
+        }
+    }
+}
+
+/// A deleted message that a resuming client may still be showing.
+struct TombstoneRow {
+    id: MessageId,
+    changed_seq: i64,
+    deleted_at: i64,
+}
+
+impl From<TombstoneRow> for ServerMessage {
+    fn from(row: TombstoneRow) -> Self {
+        ServerMessage::ephemeral(
+            row.changed_seq as Seq,
+            row.deleted_at,
+            ChatMessage::DeleteMessage { id: row.id },
+        )
+    }
+}
+
+/// Fill in the reactions and reply counts of the messages.
+async fn annotate(
+    pool: &SqlitePool,
+    mut messages: Vec<ServerMessage>,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let ids = messages.iter().filter_map(|msg| msg.id).collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Ok(messages);
+    }
+    // SQLite can't bind a list, so the IDs are passed as a JSON array instead.
+    let ids = serde_json::to_string(&ids).unwrap();
+    let rows = query!(
+        "SELECT message_id, emoji, username FROM reaction
+        WHERE message_id IN (SELECT value FROM json_each(?)) ORDER BY created_at ASC, rowid ASC",
+        ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut reactions: HashMap<MessageId, Vec<ReactionSummary>> = HashMap::new();
+    for row in rows {
+        let summaries = reactions.entry(row.message_id).or_default();
+        match summaries.iter_mut().find(|summary| summary.emoji == row.emoji) {
+            Some(summary) => summary.users.push(row.username),
+            None => summaries.push(ReactionSummary {
+                emoji: row.emoji,
+                users: vec![row.username],
+            }),
+        }
+    }
+    let reply_counts = query!(
+        r#"SELECT reply_to AS "reply_to!: MessageId", COUNT(*) AS "count!: i64" FROM message
+        WHERE reply_to IN (SELECT value FROM json_each(?)) AND deleted_at IS NULL GROUP BY reply_to"#,
+        ids
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.reply_to, row.count as u32))
+    .collect::<HashMap<_, _>>();
+    for msg in &mut messages {
+        if let Some(summaries) = msg.id.and_then(|id| reactions.remove(&id)) {
+            msg.reactions = summaries;
+        }
+        if let Some(count) = msg.id.and_then(|id| reply_counts.get(&id)) {
+            msg.reply_count = *count;
+        }
+    }
+    Ok(messages)
+}
+
+/// Add or remove a user's reaction to a message, as the change with sequence number `seq`.
+/// Returns the message that was reacted to, or `None` if there is no such message.
+pub async fn set_reaction(
+    pool: &SqlitePool,
+    message_id: MessageId,
+    username: &str,
+    emoji: &str,
+    add: bool,
+    seq: Seq,
+    created_at: i64,
+) -> anyhow::Result<Option<ServerMessage>> {
+    let Some(message) = fetch_message(pool, message_id).await? else {
+        return Ok(None);
+    };
+    if add {
+        query!(
+            "INSERT OR IGNORE INTO reaction (message_id, username, emoji, created_at) VALUES (?, ?, ?, ?)",
+            message_id,
+            username,
+            emoji,
+            created_at
+        )
+        .execute(pool)
+        .await?;
+    } else {
+        query!(
+            "DELETE FROM reaction WHERE message_id = ? AND username = ? AND emoji = ?",
+            message_id,
+            username,
+            emoji
+        )
+        .execute(pool)
+        .await?;
+    }
+    mark_changed(pool, message_id, seq).await?;
+    Ok(Some(message))
+}
+
+/// Record that a message changed with sequence number `seq`, so that resuming clients are sent the change.
+async fn mark_changed<'c, E>(executor: E, id: MessageId, seq: Seq) -> anyhow::Result<()>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Sqlite>,
+{
+    let seq = seq as i64;
+    query!("UPDATE message SET changed_seq = ? WHERE id = ?", seq, id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Fetch a single message from the history.
+pub async fn fetch_message(pool: &SqlitePool, id: MessageId) -> anyhow::Result<Option<ServerMessage>> {
+    let row = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE id = ? AND deleted_at IS NULL"#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(annotate(pool, row.into_iter().map(ServerMessage::from).collect()).await?.pop())
+}
+
+/// Replace the content of a stored message, keeping the version it had before as a revision.
+/// `seq` is the sequence number of the edit.
+/// Returns the message as it is after the edit, or `None` if there is no such message.
+pub async fn apply_edit(
+    pool: &SqlitePool,
+    id: MessageId,
+    new_content: &str,
+    signature: Option<&str>,
+    seq: Seq,
+    edited_at: i64,
+) -> anyhow::Result<Option<ServerMessage>> {
+    let mut tx = pool.begin().await?;
+    let previous = query!(
+        r#"SELECT content, signature, COALESCE(edited_at, created_at) AS "written_at!: i64" FROM message
+        WHERE id = ? AND deleted_at IS NULL"#,
+        id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let Some(previous) = previous else {
+        return Ok(None);
+    };
+    query!(
+        "INSERT INTO message_revision (message_id, created_at, content, signature) VALUES (?, ?, ?, ?)",
+        id,
+        previous.written_at,
+        previous.content,
+        previous.signature
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        "UPDATE message SET content = ?, signature = ?, edited_at = ? WHERE id = ?",
+        new_content,
+        signature,
+        edited_at,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    mark_changed(&mut tx, id, seq).await?;
+    tx.commit().await?;
+    fetch_message(pool, id).await
+}
+
+/// Fetch the earlier versions of a message, oldest first.
+pub async fn fetch_revisions(pool: &SqlitePool, id: MessageId) -> anyhow::Result<Vec<MessageRevision>> {
+    let revisions = query_as!(
+        MessageRevision,
+        "SELECT content, signature, created_at AS timestamp FROM message_revision WHERE message_id = ? ORDER BY id ASC",
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(revisions)
+}
+
+/// Delete a message, leaving only a tombstone without its content or earlier versions.
+/// `seq` is the sequence number of the deletion.
+/// Returns the message as it was, so that the deletion can be sent to everyone who could see it,
+/// or `None` if there is no such message (or it was already deleted).
+pub async fn tombstone_message(
+    pool: &SqlitePool,
+    id: MessageId,
+    seq: Seq,
+    deleted_at: i64,
+) -> anyhow::Result<Option<ServerMessage>> {
+    let Some(original) = fetch_message(pool, id).await? else {
+        return Ok(None);
+    };
+    let mut tx = pool.begin().await?;
+    query!(
+        "UPDATE message SET content = '', signature = NULL, deleted_at = ? WHERE id = ?",
+        deleted_at,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    query!("DELETE FROM message_revision WHERE message_id = ?", id)
+        .execute(&mut tx)
+        .await?;
+    query!("DELETE FROM reaction WHERE message_id = ?", id)
+        .execute(&mut tx)
+        .await?;
+    mark_changed(&mut tx, id, seq).await?;
+    tx.commit().await?;
+    Ok(Some(original))
+}
+
+// The room queries below also include the system messages that are shown in every room (with a NULL room).
+// Replies are only shown in their threads, so the recent messages and pages of a room leave them out;
+// but the messages after a point include them, so that reconnecting clients don't miss any.
+
+/// Fetch the last `limit` messages in the room, oldest first.
+pub async fn fetch_recent(
+    pool: &SqlitePool,
+    room: &str,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE (room = ? OR (room IS NULL AND kind = 'system')) AND deleted_at IS NULL AND reply_to IS NULL ORDER BY id DESC LIMIT ?"#,
+        room,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    annotate(pool, rows.into_iter().rev().map(ServerMessage::from).collect()).await
+}
+
+/// Fetch up to `limit` messages in the room that came after the message with ID `since`, oldest first.
+pub async fn fetch_since(
+    pool: &SqlitePool,
+    room: &str,
+    since: MessageId,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE (room = ? OR (room IS NULL AND kind = 'system')) AND deleted_at IS NULL AND id > ? ORDER BY id ASC LIMIT ?"#,
+        room,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    annotate(pool, rows.into_iter().map(ServerMessage::from).collect()).await
+}
+
+/// Fetch up to `limit` messages in the room with a sequence number after `since`, oldest first,
+/// followed by the older messages that were edited, deleted or reacted to after `since`.
+///
+/// Changed messages are sent as they are now, and deleted ones as a `DeleteMessage`.
+/// They come after the new messages, so that their reply counts already include any new replies.
+pub async fn fetch_after_seq(
+    pool: &SqlitePool,
+    room: &str,
+    since: Seq,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let since = since as i64;
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE (room = ? OR (room IS NULL AND kind = 'system')) AND deleted_at IS NULL AND seq > ? ORDER BY seq ASC LIMIT ?"#,
+        room,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let changed = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE room = ? AND deleted_at IS NULL AND seq <= ? AND changed_seq > ? ORDER BY changed_seq ASC LIMIT ?"#,
+        room,
+        since,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let deleted = query_as!(
+        TombstoneRow,
+        r#"SELECT id AS "id!", changed_seq AS "changed_seq!", deleted_at AS "deleted_at!" FROM message
+        WHERE room = ? AND deleted_at IS NOT NULL AND seq <= ? AND changed_seq > ? ORDER BY changed_seq ASC LIMIT ?"#,
+        room,
+        since,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut messages = annotate(pool, rows.into_iter().chain(changed).map(ServerMessage::from).collect()).await?;
+    messages.extend(deleted.into_iter().map(ServerMessage::from));
+    Ok(messages)
+}
+
+/// Fetch up to `limit` messages in the room that came before the message with ID `before`
+/// (or the newest messages, if `before` is `None`), oldest first.
+pub async fn fetch_before(
+    pool: &SqlitePool,
+    room: &str,
+    before: Option<MessageId>,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let before = before.unwrap_or(MessageId::MAX);
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE (room = ? OR (room IS NULL AND kind = 'system')) AND deleted_at IS NULL AND reply_to IS NULL AND id < ? ORDER BY id DESC LIMIT ?"#,
+        room,
+        before,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    annotate(pool, rows.into_iter().rev().map(ServerMessage::from).collect()).await
+}
+
+/// Fetch the last `limit` direct messages sent by or to the user, oldest first.
+pub async fn fetch_direct_recent(
+    pool: &SqlitePool,
+    username: &str,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE kind = 'direct' AND (username = ? OR recipient = ?) AND deleted_at IS NULL ORDER BY id DESC LIMIT ?"#,
+        username,
+        username,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    annotate(pool, rows.into_iter().rev().map(ServerMessage::from).collect()).await
+}
+
+/// Fetch up to `limit` direct messages sent by or to the user with a sequence number after `since`, oldest first,
+/// followed by the older ones that changed after `since`, like `fetch_after_seq`.
+pub async fn fetch_direct_after_seq(
+    pool: &SqlitePool,
+    username: &str,
+    since: Seq,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let since = since as i64;
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE kind = 'direct' AND (username = ? OR recipient = ?) AND deleted_at IS NULL AND seq > ? ORDER BY seq ASC LIMIT ?"#,
+        username,
+        username,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let changed = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE kind = 'direct' AND (username = ? OR recipient = ?) AND deleted_at IS NULL AND seq <= ? AND changed_seq > ?
+        ORDER BY changed_seq ASC LIMIT ?"#,
+        username,
+        username,
+        since,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let deleted = query_as!(
+        TombstoneRow,
+        r#"SELECT id AS "id!", changed_seq AS "changed_seq!", deleted_at AS "deleted_at!" FROM message
+        WHERE kind = 'direct' AND (username = ? OR recipient = ?) AND deleted_at IS NOT NULL AND seq <= ? AND changed_seq > ?
+        ORDER BY changed_seq ASC LIMIT ?"#,
+        username,
+        username,
+        since,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut messages = annotate(pool, rows.into_iter().chain(changed).map(ServerMessage::from).collect()).await?;
+    messages.extend(deleted.into_iter().map(ServerMessage::from));
+    Ok(messages)
+}
+
+/// Fetch the replies to a message, oldest first.
+pub async fn fetch_replies(
+    pool: &SqlitePool,
+    parent: MessageId,
+    limit: i64,
+) -> anyhow::Result<Vec<ServerMessage>> {
+    let rows = query_as!(
+        MessageRow,
+        r#"SELECT id AS "id!", seq AS "seq!", created_at AS "created_at!", edited_at, kind AS "kind!", room, username, recipient,
+            content AS "content!", signature, encrypted AS "encrypted!", reply_to FROM message
+        WHERE reply_to = ? AND deleted_at IS NULL ORDER BY id ASC LIMIT ?"#,
+        parent,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    annotate(pool, rows.into_iter().map(ServerMessage::from).collect()).await
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    room: Option<String>,
+    before: Option<MessageId>,
+    limit: Option<i64>,
+}
+
+pub async fn get_messages(
+    State(appstate): State<AppState>,
+    Query(params): Query<HistoryParams>,
+) -> Response<String> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let room = params.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    match fetch_before(&appstate.pool, room, params.before, limit).await {
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+        Ok(messages) => {
+            // If the page isn't full, we've reached the start of the history.
+            let next_before = if messages.len() as i64 == limit {
+                messages.first().and_then(|msg| msg.id)
+            } else {
+                None
+            };
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&HistoryPage { messages, next_before }).unwrap())
+                .unwrap()
+        }
+    }
+}
+
+pub async fn get_revisions(
+    State(appstate): State<AppState>,
+    Path(id): Path<MessageId>,
+) -> Response<String> {
+    async fn inner_get_revisions(
+        appstate: AppState,
+        id: MessageId,
+    ) -> anyhow::Result<Response<String>> {
+        let pool = &appstate.pool;
+        match fetch_message(pool, id).await?.map(|msg| msg.message) {
+            Some(ChatMessage::TextMessage { .. }) => {}
+            // Direct messages are private, and this endpoint can't tell who is asking
+            Some(ChatMessage::DirectMessage { .. }) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body("revisions of direct messages are not public".to_string())
+                    .unwrap())
+            }
+            _ => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body("no text message with this ID".to_string())
+                    .unwrap())
+            }
+        }
+        let revisions = fetch_revisions(pool, id).await?;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&revisions).unwrap())
+            .unwrap())
+    }
+
+    match inner_get_revisions(appstate, id).await {
+        Ok(res) => res,
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+    }
+}
+
+pub async fn get_thread(
+    State(appstate): State<AppState>,
+    Path(id): Path<MessageId>,
+) -> Response<String> {
+    async fn inner_get_thread(appstate: AppState, id: MessageId) -> anyhow::Result<Response<String>> {
+        let pool = &appstate.pool;
+        // Only text messages can have replies, and direct messages are private anyway
+        let parent = match fetch_message(pool, id).await? {
+            Some(parent @ ServerMessage { message: ChatMessage::TextMessage { .. }, .. }) => parent,
+            _ => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body("no text message with this ID".to_string())
+                    .unwrap())
+            }
+        };
+        let replies = fetch_replies(pool, id, MAX_BACKFILL).await?;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&Thread { parent, replies }).unwrap())
+            .unwrap())
+    }
+
+    match inner_get_thread(appstate, id).await {
+        Ok(res) => res,
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+    }
+}
@@ -1,30 +1,40 @@
 #![feature(async_closure)]
-use std::{borrow::Cow, env, error::Error, path::PathBuf};
+use std::{env, error::Error, path::PathBuf};
 
 use axum::{
-    extract::{
-        ws::{close_code, CloseFrame, Message, WebSocket},
-        Path, State, WebSocketUpgrade,
-    },
+    extract::{Path, State},
     http::StatusCode,
     response::Response,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use base64::Engine;
 use common::ChatMessage;
 use k256::PublicKey;
+use message_manager::{RoomRegistry, UserRegistry};
 use notification::get_notification_router;
-use rand::{seq::SliceRandom, SeedableRng};
+use push_client::PushClient;
+use push_config::PushConfig;
+use rooms::get_rooms_router;
 use sqlx::{query, SqlitePool};
 use tokio::sync::{broadcast, mpsc};
 use tower_http::services::ServeDir;
-use web_push::{VapidSignatureBuilder, WebPushClient, PartialVapidSignatureBuilder};
+use web_push::{VapidSignatureBuilder, PartialVapidSignatureBuilder};
 
 use crate::notification::notification_receiver_loop;
 
+mod coalescing;
+mod deletion;
+mod history;
 mod message_manager;
 mod notification;
+mod preferences;
+mod push_client;
+mod push_config;
+mod rooms;
+mod search;
+mod socket;
+mod users;
 
 fn say_wrong_keys() {
     println!("VAPID_PRIVATE_KEY is not set or invalid!");
@@ -35,19 +45,15 @@ fn say_wrong_keys() {
 pub struct AppState {
     pub pool: SqlitePool,
     pub message_manager_tx: mpsc::Sender<ChatMessage>,
-    message_manager_broadcaster: broadcast::Sender<ChatMessage>,
-    pub webpush_client: WebPushClient,
+    pub rooms: RoomRegistry,
+    pub users: UserRegistry,
+    pub webpush_client: PushClient,
     pub webpush_signer: PartialVapidSignatureBuilder,
     pub webpush_server_url: String,
+    pub push_config: PushConfig,
 
 }
 
-impl AppState {
-    pub fn get_receiver(&self) -> broadcast::Receiver<ChatMessage> {
-        self.message_manager_broadcaster.subscribe()
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenvy::from_filename("./backend/.env").expect("Error while loading .env file");
@@ -57,6 +63,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         SqlitePool::connect(&env::var("DATABASE_URL").expect("no DATABASE_URL in .env file?"))
             .await?;
     sqlx::migrate!().run(&pool).await?;
+    history::init_sequence(&pool).await?;
 
     let maybe_vapid_private_key = env::var("VAPID_PRIVATE_KEY");
     let vapid_private_key;
@@ -73,15 +80,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let (message_manager_tx, message_manager_rx) = mpsc::channel(100);
     let (message_broadcaster_tx, message_broadcaster_rx) = broadcast::channel(100);
+    let rooms = RoomRegistry::default();
+    let users = UserRegistry::default();
 
     tokio::spawn(message_manager::manage_messages(
+        pool.clone(),
         message_manager_rx,
-        message_broadcaster_tx.clone(),
+        message_broadcaster_tx,
+        rooms.clone(),
+        users.clone(),
     ));
 
 
     let vapid_private_key = vapid_private_key.trim();
-    let client = WebPushClient::new()?;
+    let client = PushClient::new()?;
     let signer =
         VapidSignatureBuilder::from_base64_no_sub(vapid_private_key, web_push::URL_SAFE_NO_PAD);
     if signer.is_err() {
@@ -99,27 +111,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let server_url = env::var("SERVER_URL").expect("SERVER_URL should be set in .env file");
 
 
-    tokio::spawn(notification_receiver_loop(pool.clone(), signer.clone(), client.clone(), message_broadcaster_rx));
+    let push_config = PushConfig::from_env()?;
+    tokio::spawn(notification_receiver_loop(pool.clone(), signer.clone(), client.clone(), push_config.clone(), users.clone(), message_broadcaster_rx));
 
 
     let appstate = AppState {
         pool,
         message_manager_tx,
-        message_manager_broadcaster: message_broadcaster_tx,
+        rooms,
+        users,
         webpush_client: client,
         webpush_signer: signer,
         webpush_server_url: server_url,
+        push_config,
     };
 
     let app = Router::<AppState>::new()
-        .route("/ws", get(handle_websocket_connection))
+        .route("/ws", get(socket::handle_websocket_connection))
         .route("/vapid_public_key", get(get_pubkey))
         .route("/register/:username", post(register_username))
         .route("/pubkey/:username", get(get_pubkey_by_username))
+        .route("/messages", get(history::get_messages))
+        .route("/messages/:id", delete(deletion::delete_message))
+        .route("/messages/:id/revisions", get(history::get_revisions))
+        .route("/messages/:id/thread", get(history::get_thread))
+        .route("/search", get(search::get_search))
         .nest(
             "/notification",
             get_notification_router(),
         )
+        .nest("/rooms", get_rooms_router())
         .nest_service(
             "/",
             ServeDir::new(
@@ -146,10 +167,8 @@ async fn get_pubkey_by_username(
     Path(username): Path<String>,
 ) -> Response<String> {
     let pool = &appstate.pool;
-    let maybe_existing_user = query!("SELECT * FROM user WHERE name=?", username)
-        .fetch_optional(pool)
-        .await;
-    match maybe_existing_user {
+    let maybe_existing_key = users::find_public_key_jwk(pool, &username).await;
+    match maybe_existing_key {
         Err(why) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body(format!("database error: {why}"))
@@ -158,9 +177,9 @@ async fn get_pubkey_by_username(
             .status(StatusCode::NOT_FOUND)
             .body("no user found with this username".to_string())
             .unwrap(),
-        Ok(Some(data)) => Response::builder()
+        Ok(Some(public_key)) => Response::builder()
             .status(StatusCode::OK)
-            .body(data.public_key)
+            .body(public_key)
             .unwrap(),
     }
 }
@@ -226,108 +245,3 @@ async fn register_username(
         }
     }
 }
-
-async fn handle_websocket_connection(
-    State(appstate): State<AppState>,
-    ws: WebSocketUpgrade,
-) -> axum::response::Response {
-    let sender = appstate.message_manager_tx.clone();
-    let receiver = appstate.get_receiver();
-    ws.on_upgrade(|ws| handle_socket(ws, sender, receiver))
-}
-
-async fn handle_socket(
-    mut socket: WebSocket,
-    message_sender: mpsc::Sender<ChatMessage>,
-    mut message_receiver: broadcast::Receiver<ChatMessage>,
-) {
-    // Generate a username to use for simple messages
-
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let mut name = String::from("Anonymous User ");
-    let letters = [
-        '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
-    ];
-    for _ in 0..4 {
-        let letter = letters.choose(&mut rng).unwrap();
-        name.push(*letter);
-    }
-
-    loop {
-        tokio::select! {
-            maybe_client_msg = socket.recv() => {
-                if let Some(msg) = maybe_client_msg {
-                    let msg = if let Ok(msg) = msg {
-                        msg
-                    } else {
-                        // client disconnected
-                        message_sender.send(ChatMessage::SystemMessage { content: format!("{name} disconnected from chat") }).await.unwrap();
-                        return;
-                    };
-
-                    match msg {
-                        Message::Text(data) => {
-                            // Try to parse the message as a ChatMessage struct.
-                            // If we fail, send this message as an anonymous message with no signature.
-
-                            let maybe_parsed_msg: Result<ChatMessage, _> = serde_json::from_str(&data);
-                            let process_incoming_msg = async move |data: &str, maybe_parsed_msg: Result<ChatMessage, serde_json::Error>, message_sender: &mpsc::Sender<ChatMessage>, socket: &mut WebSocket, name: &mut String| -> anyhow::Result<()> {
-                                match maybe_parsed_msg {
-                                    Ok(msg) => {
-                                        match msg {
-                                            ChatMessage::TextMessage { .. } => message_sender.send(msg).await?,
-                                            ChatMessage::SystemMessage { .. } => socket.send(
-                                                Message::Text(
-                                                    serde_json::to_string(&ChatMessage::SystemMessage { content: format!("Cannot send system messages") }).unwrap()
-                                                )
-                                            ).await?,
-                                            ChatMessage::ConnectionUsername { username } => {
-                                                name.clear();
-                                                name.extend(username.chars());
-                                                message_sender.send(ChatMessage::SystemMessage { content: format!("{username} connected to chat") }).await?;
-                                            }
-                                        }
-
-                                    },
-                                    Err(_) => message_sender.send(ChatMessage::TextMessage { username: name.to_string(), content: data.to_string(), signature: None }).await?,
-                                };
-                                Ok(())
-                            };
-
-                            match process_incoming_msg(&data, maybe_parsed_msg, &message_sender, &mut socket, &mut name).await {
-                                Ok(_) => {},
-                                Err(_) => {eprintln!("Error while sending message to message manager (are we shutting down?)")},
-                            }
-                        }
-                        _ => {}
-                    }
-                } else {
-                    // client disconnected
-                    message_sender.send(ChatMessage::SystemMessage { content: format!("{name} disconnected from chat") }).await.unwrap();
-                    return;
-                }
-            }
-
-            maybe_server_msg = message_receiver.recv() => {
-                match maybe_server_msg {
-                    Err(_) => {
-                        #[allow(unused_must_use)]
-                        {
-                        socket.send(Message::Close(Some(CloseFrame{ code: close_code::ABNORMAL, reason: Cow::from("Error while retreiving other members' messages (maybe server going down?)") }))).await;
-                        socket.close();
-                        message_sender.send(ChatMessage::SystemMessage { content: format!("{name} disconnected from chat") }).await.unwrap();
-                        }
-                        return;
-                    },
-                    Ok(msg) => {
-                        if socket.send(Message::Text(serde_json::to_string(&msg).unwrap())).await.is_err() {
-                            // Probably client disconnected?
-                            message_sender.send(ChatMessage::SystemMessage { content: format!("{name} disconnected from chat") }).await.unwrap();
-                            return;
-                        }
-                    },
-                }
-            }
-        };
-    }
-}
@@ -1,9 +1,186 @@
-use common::ChatMessage;
-use tokio::sync::{broadcast, mpsc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::{ChatMessage, ServerMessage};
+use sqlx::SqlitePool;
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TrySendError},
+    Notify,
+};
+
+use crate::history::{apply_edit, next_seq, now_millis, set_reaction, store_message, tombstone_message};
+
+/// How many messages each room's broadcaster buffers for receivers that fall behind.
+const ROOM_CHANNEL_CAPACITY: usize = 100;
+
+/// What gets sent to a websocket connection's outgoing channel.
+/// The only error that is sent is `RecvError::Closed`, when the server is shutting down.
+pub type Outgoing = Result<ServerMessage, broadcast::error::RecvError>;
+
+/// ID of a websocket connection, so that it can be removed from the registries when it closes.
+pub type ConnectionId = u64;
+
+/// If a user comes back within this long after their last connection closed, nobody is told that they left or came back.
+pub const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(15);
+
+/// Keeps a broadcaster for every room that someone is listening to.
+#[derive(Clone, Default)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<HashMap<String, broadcast::Sender<ServerMessage>>>>,
+}
+
+impl RoomRegistry {
+    /// Start receiving the messages sent to a room.
+    pub fn subscribe(&self, room: &str) -> broadcast::Receiver<ServerMessage> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| broadcast::channel(ROOM_CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Send a message to everyone in a room.
+    fn send(&self, room: &str, message: ServerMessage) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(sender) = rooms.get(room) {
+            if sender.send(message).is_err() {
+                // Nobody is in this room anymore, so there's no need to keep its broadcaster around.
+                rooms.remove(room);
+            }
+        }
+    }
+
+    /// Send a message to everyone in every room.
+    fn send_to_all(&self, message: ServerMessage) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, sender| sender.send(message.clone()).is_ok());
+    }
+}
+
+/// Where a user's direct messages go on one of their connections.
+#[derive(Clone)]
+pub struct UserConnection {
+    pub sender: mpsc::Sender<Outgoing>,
+    /// Notified when the connection can't keep up and should be closed.
+    pub kick: Arc<Notify>,
+}
+
+/// The connections of every authenticated user, by username.
+type UserConnections = HashMap<String, HashMap<ConnectionId, UserConnection>>;
+
+/// Keeps track of which connections are authenticated as which user,
+/// so that direct messages can be delivered only to the people in the conversation.
+#[derive(Clone, Default)]
+pub struct UserRegistry {
+    users: Arc<Mutex<UserConnections>>,
+    /// When each user's last connection closed, for users that might still come back within the grace period.
+    left: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl UserRegistry {
+    pub fn register(&self, username: &str, connection: ConnectionId, user_connection: UserConnection) {
+        let mut users = self.users.lock().unwrap();
+        users
+            .entry(username.to_string())
+            .or_default()
+            .insert(connection, user_connection);
+    }
+
+    pub fn unregister(&self, username: &str, connection: ConnectionId) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(connections) = users.get_mut(username) {
+            connections.remove(&connection);
+            if connections.is_empty() {
+                users.remove(username);
+            }
+        }
+    }
+
+    pub fn is_connected(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    /// Whether the user is still connected somewhere else, or is coming back within the grace period after leaving.
+    /// Call this before registering the new connection.
+    pub fn is_returning(&self, username: &str) -> bool {
+        let left_at = self.left.lock().unwrap().remove(username);
+        self.is_connected(username)
+            || left_at.is_some_and(|left_at| left_at.elapsed() < RECONNECT_GRACE_PERIOD)
+    }
+
+    /// Remember that one of the user's connections closed, returning when.
+    pub fn mark_left(&self, username: &str) -> Instant {
+        let now = Instant::now();
+        self.left.lock().unwrap().insert(username.to_string(), now);
+        now
+    }
+
+    /// Whether the user really left at `left_at`: they have no connections, and haven't come back and left again since.
+    /// Call this once the grace period is over.
+    pub fn has_left(&self, username: &str, left_at: Instant) -> bool {
+        let mut left = self.left.lock().unwrap();
+        if left.get(username) == Some(&left_at) && !self.is_connected(username) {
+            left.remove(username);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Send a message to every connection authenticated as this user.
+    ///
+    /// This never waits for a connection: one that is too slow to keep up is dropped from the registry and kicked,
+    /// and gets the message from the history when it reconnects.
+    fn send(&self, username: &str, message: ServerMessage) {
+        let mut users = self.users.lock().unwrap();
+        let Some(connections) = users.get_mut(username) else {
+            return;
+        };
+        connections.retain(|_, connection| match connection.sender.try_send(Ok(message.clone())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Disconnecting a connection of {username} that can't keep up with its direct messages");
+                connection.kick.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+        if connections.is_empty() {
+            users.remove(username);
+        }
+    }
+}
+
+/// Deliver a message to the sockets that should see it.
+/// Who that is depends on `about`: the message itself, or for an edit, the message that was edited.
+fn deliver(rooms: &RoomRegistry, users: &UserRegistry, about: &ChatMessage, message: ServerMessage) {
+    match about {
+        ChatMessage::TextMessage { room, .. }
+        | ChatMessage::SystemMessage {
+            room: Some(room), ..
+        } => rooms.send(room, message),
+        ChatMessage::SystemMessage { room: None, .. } => rooms.send_to_all(message),
+        // Direct messages never go through the rooms, only to the people in the conversation.
+        ChatMessage::DirectMessage { from, to, .. } => {
+            users.send(from, message.clone());
+            if to != from {
+                users.send(to, message);
+            }
+        }
+        _ => {}
+    }
+}
 
 pub async fn manage_messages(
+    pool: SqlitePool,
     mut message_manager_rx: mpsc::Receiver<ChatMessage>,
-    message_broadcaster_tx: broadcast::Sender<ChatMessage>,
+    message_broadcaster_tx: broadcast::Sender<ServerMessage>,
+    rooms: RoomRegistry,
+    users: UserRegistry,
 ) {
     // Loop waiting for new messages
     loop {
@@ -11,8 +188,74 @@ pub async fn manage_messages(
             .recv()
             .await
             .expect("Message channel is closing");
+        // Messages are numbered in the order they arrive here, so the sequence numbers of the stored ones never go backwards.
+        let seq = next_seq();
+        let timestamp = now_millis();
+
+        // Edits, deletions and reactions change a stored message instead of adding one,
+        // and go to everyone who could see that message.
+        let changed = match &new_message {
+            ChatMessage::EditMessage {
+                id,
+                new_content,
+                signature,
+            } => Some(apply_edit(&pool, *id, new_content, signature.as_deref(), seq, timestamp).await),
+            ChatMessage::DeleteMessage { id } => Some(tombstone_message(&pool, *id, seq, timestamp).await),
+            ChatMessage::Reaction {
+                message_id,
+                emoji,
+                add,
+                username,
+            } => Some(set_reaction(&pool, *message_id, username, emoji, *add, seq, timestamp).await),
+            _ => None,
+        };
+        if let Some(changed) = changed {
+            let changed = match changed {
+                Ok(Some(changed)) => changed,
+                Ok(None) => continue,
+                Err(why) => {
+                    eprintln!("Error changing message in history: {why}");
+                    continue;
+                }
+            };
+            let edited_at = matches!(new_message, ChatMessage::EditMessage { .. }).then_some(timestamp);
+            let message = ServerMessage {
+                id: None,
+                seq,
+                timestamp,
+                edited_at,
+                reactions: Vec::new(),
+                reply_count: 0,
+                message: new_message,
+            };
+            deliver(&rooms, &users, &changed.message, message);
+            continue;
+        }
+
+        // Save the message to the history before anyone sees it.
+        // If this fails, the message is still delivered: a live chat is more important than a complete history.
+        let id = match store_message(&pool, seq, timestamp, &new_message).await {
+            Ok(id) => id,
+            Err(why) => {
+                eprintln!("Error saving message to history: {why}");
+                None
+            }
+        };
+        let message = ServerMessage {
+            id,
+            seq,
+            timestamp,
+            edited_at: None,
+            reactions: Vec::new(),
+            reply_count: 0,
+            message: new_message,
+        };
+
+        // Deliver the message to the sockets in its room
+        deliver(&rooms, &users, &message.message, message.clone());
+
         // Once a message is received, broadcast it to the channel
-        match message_broadcaster_tx.send(new_message) {
+        match message_broadcaster_tx.send(message) {
             Ok(_) => {}
             Err(_) => {
                 eprintln!("Error sending message into the broadcaster transmitter (all message receivers are down?!)")
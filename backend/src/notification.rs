@@ -1,29 +1,63 @@
-use axum::{extract::State, http::StatusCode, response::Response, routing::post, Json, Router};
-use common::ChatMessage;
-use serde::{Serialize};
-use sqlx::{query, SqlitePool};
+use std::{collections::HashMap, time::Duration};
+
+use axum::{extract::State, http::StatusCode, response::Response, routing::{get, post}, Json, Router};
+use common::{ChatMessage, MessageId, NotificationPreferences, ServerMessage, DEFAULT_ROOM};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, SqlitePool};
 use tokio::sync::broadcast;
-use web_push::{PartialVapidSignatureBuilder, SubscriptionInfo, WebPushClient, WebPushMessageBuilder};
+use web_push::{PartialVapidSignatureBuilder, SubscriptionInfo, WebPushError, WebPushMessageBuilder};
 
-use crate::AppState;
+use crate::{coalescing::{Coalescer, Conversation}, history, message_manager::UserRegistry, preferences::{self, PushReason}, push_client::PushClient, push_config::{PushConfig, PushKind, PushParams}, users::{self, SignatureCheck}, AppState};
 
 pub fn get_notification_router(
 ) -> Router<AppState> {
     Router::new()
         .route("/register", post(add_registration))
         .route("/unregister", post(remove_registration))
+        .route("/preferences", get(preferences::get_preferences).post(preferences::set_preferences))
+}
+
+#[derive(Deserialize)]
+struct Registration {
+    #[serde(flatten)]
+    subscription: SubscriptionInfo,
+    /// The user that this subscription belongs to. Notifications meant for them are pushed to it.
+    username: String,
+    /// Signature over `signing::push_subscription_payload(username, endpoint)`, to prove that the subscriber is that user.
+    signature: Option<String>,
 }
 
 async fn add_registration(
     State(appstate): State<AppState>,
-    Json(data): Json<SubscriptionInfo>,
+    Json(registration): Json<Registration>,
 ) -> Response<String> {
     let pool = &appstate.pool;
+    let data = registration.subscription;
+    let username = &registration.username;
+    // Only registered users can subscribe, since the subscription has to be tied to their account
+    let payload = common::signing::push_subscription_payload(username, &data.endpoint);
+    let check = users::check_signature(pool, username, &payload, registration.signature.as_deref()).await;
+    match check {
+        Ok(SignatureCheck::Valid) => {},
+        Ok(_) => {
+            let mut resp = Response::new(format!("could not prove that this subscription belongs to {username}"));
+            *resp.status_mut() = StatusCode::UNAUTHORIZED;
+            return resp;
+        },
+        Err(why) => {
+            let mut resp = Response::new(format!("database error: {why}"));
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return resp;
+        },
+    }
+
     let result = query!(
-        "INSERT INTO subscription (endpoint, p256dh, auth) VALUES (?,?,?)",
+        "INSERT INTO subscription (endpoint, p256dh, auth, username) VALUES (?,?,?,?)
+        ON CONFLICT (endpoint) DO UPDATE SET p256dh = excluded.p256dh, auth = excluded.auth, username = excluded.username, consecutive_failures = 0",
         data.endpoint,
         data.keys.p256dh,
-        data.keys.auth
+        data.keys.auth,
+        registration.username
     )
     .execute(pool)
     .await;
@@ -32,7 +66,7 @@ async fn add_registration(
         *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
     }
 
-    test_notification(&data.into(), &appstate.webpush_signer, &appstate.webpush_client).await.expect("failed to send notification?");
+    test_notification(&data, &appstate.webpush_signer, &appstate.webpush_client, &appstate.push_config).await.expect("failed to send notification?");
     return resp;
 }
 
@@ -54,27 +88,94 @@ async fn remove_registration(
     return resp;
 }
 
-#[derive(Serialize)]
-struct Notification {
+/// A row of the `subscription` table.
+#[derive(Clone)]
+pub(crate) struct Subscription {
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+    pub username: String,
+}
+
+/// A group of subscriptions that get the same notification about a message.
+struct Batch {
+    subscriptions: Result<Vec<Subscription>, sqlx::Error>,
+    notification: Notification,
+    kind: PushKind,
+    reason: PushReason,
+    /// For notifications that can be coalesced with others about the same conversation,
+    /// the conversation and who sent the message.
+    coalesce: Option<(Conversation, String)>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct Notification {
     pub title: String,
     pub body: String,
     /// If true, the browser is instructed to show the notification even if it is currently focused.
     pub always_show: bool,
+    /// Notifications with the same tag are grouped by the service worker: a newer one replaces an older one.
+    pub tag: String,
+    #[serde(flatten)]
+    pub link: NotificationLink,
+}
+
+/// Where clicking a notification takes the user.
+#[derive(Serialize, Clone, Default)]
+pub(crate) struct NotificationLink {
+    /// The message that the notification is about.
+    pub message_id: Option<MessageId>,
+    /// The room that the message is in, or `None` for direct messages.
+    pub room: Option<String>,
+    /// The page of the app that shows the message, relative to the server.
+    pub url: String,
+}
+
+/// Percent-encode a value for a URL query string.
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+impl NotificationLink {
+    /// `view` is what the app shows: a room name, or `@username` for a conversation with that user.
+    /// Replies are shown in the thread of the message they reply to.
+    fn new(message_id: Option<MessageId>, room: Option<String>, view: &str, thread: Option<MessageId>) -> Self {
+        let mut url = format!("/?room={}", encode_query_value(view));
+        if let Some(id) = message_id {
+            url.push_str(&format!("&message={id}"));
+        }
+        if let Some(thread) = thread {
+            url.push_str(&format!("&thread={thread}"));
+        }
+        NotificationLink { message_id, room, url }
+    }
 }
 
-pub async fn test_notification(info: &SubscriptionInfo, signer: &PartialVapidSignatureBuilder, client: &WebPushClient) -> anyhow::Result<()> {
+pub async fn test_notification(info: &SubscriptionInfo, signer: &PartialVapidSignatureBuilder, client: &PushClient, config: &PushConfig) -> anyhow::Result<()> {
     let signer = signer.clone().add_sub_info(info);
     let mut builder = WebPushMessageBuilder::new(info)?;
-    let content = serde_json::to_vec(&Notification{ title: String::from("Test Push notification"), body: String::from("This is what incoming chat messages will look like"), always_show: true}).unwrap();
+    let content = serde_json::to_vec(&Notification{ title: String::from("Test Push notification"), body: String::from("This is what incoming chat messages will look like"), always_show: true, tag: String::from("test"), link: NotificationLink { url: String::from("/"), ..Default::default() }}).unwrap();
     builder.set_payload(web_push::ContentEncoding::Aes128Gcm, &content);
+    let params = config.params(PushKind::Test);
+    builder.set_ttl(params.ttl);
+    builder.set_urgency(params.urgency.clone());
     builder.set_vapid_signature(signer.build()?);
 
-    client.send(builder.build()?).await?;
+    client.send(builder.build()?, None).await?;
 
     Ok(())
 }
 
-pub async fn notification_receiver_loop(pool: SqlitePool, signer: PartialVapidSignatureBuilder, client: WebPushClient, mut receiver: broadcast::Receiver<ChatMessage>) {
+pub async fn notification_receiver_loop(pool: SqlitePool, signer: PartialVapidSignatureBuilder, client: PushClient, config: PushConfig, users: UserRegistry, mut receiver: broadcast::Receiver<ServerMessage>) {
+    let coalescer = Coalescer::new(pool.clone(), signer.clone(), client.clone());
     loop {
         let msg = receiver.recv().await;
         match msg {
@@ -82,49 +183,258 @@ pub async fn notification_receiver_loop(pool: SqlitePool, signer: PartialVapidSi
                 eprintln!("Error receiving message in notifier loop: {why}");
             },
             Ok(msg) => {
-                match msg {
-                    ChatMessage::TextMessage { username, content, .. } => {
-                        // Broadcast this message to all subscribers
-                        let subscriptions = sqlx::query!("SELECT * FROM subscription;").fetch_all(&pool).await;
-                        match subscriptions {
-                            Err(why) => eprintln!("Error fetching subscriptions: {why}"),
-                            Ok(subs) => {
-                                let subs = subs.iter().map(|sub| (sub.endpoint.clone(), sub.p256dh.clone(), sub.auth.clone())).collect::<Vec<_>>();
-                                let send_to_sub = async move |sub: (String, String, String), signer: PartialVapidSignatureBuilder, client: WebPushClient, username: String, content: String| -> anyhow::Result<()> {
-                                    let info = SubscriptionInfo::new(sub.0, sub.1, sub.2);
-                                    let signer = signer.add_sub_info(&info);
-                                    let mut builder = WebPushMessageBuilder::new(&info)?;
-                                    let content = serde_json::to_vec(&Notification{ title: username, body: content, always_show: false}).unwrap();
-                                    builder.set_payload(web_push::ContentEncoding::Aes128Gcm, &content);
-                                    builder.set_vapid_signature(signer.build()?);
-                                
-                                    client.send(builder.build()?).await?;
-                                    Ok(())
-                                };
-
-                                for sub in subs {
-                                    let send = {
-                                        let client = client.clone();
-                                        let signer = signer.clone();
-                                        let username = username.clone();
-                                        let content = content.clone();
-
-                                        async move {
-                                            let sub_to_send = sub.clone();
-                                            match send_to_sub(sub_to_send, signer, client, username, content).await {
-                                                Ok(_) => {},
-                                                Err(why) => eprintln!("Error sending to subscription {sub:?}: {why}"),
-                                            }
-                                        }
-                                    };
-                                    tokio::spawn(send);
-                                }
+                let seq = msg.seq;
+                let id = msg.id;
+                let batches = match msg.message {
+                    ChatMessage::TextMessage { room, username, content, reply_to, .. } => {
+                        let link = NotificationLink::new(id, Some(room.clone()), &room, reply_to);
+                        let title = if room == DEFAULT_ROOM { username.clone() } else { format!("{username} (#{room})") };
+                        // Registered users that are mentioned get a notification of their own
+                        let mentioned = common::mentions::mentioned_usernames(&content);
+                        let mentioned = match users::registered_usernames(&pool, &mentioned).await {
+                            Ok(mentioned) => mentioned.into_iter().filter(|mentioned| *mentioned != username).collect(),
+                            Err(why) => {
+                                eprintln!("Error resolving mentions: {why}");
+                                Vec::new()
                             },
+                        };
+                        // So does the author of the message being replied to, unless they were mentioned anyway
+                        let parent_author = match reply_to {
+                            None => None,
+                            Some(parent) => match history::fetch_message(&pool, parent).await {
+                                Ok(Some(ServerMessage { message: ChatMessage::TextMessage { username: parent_author, .. }, .. })) => Some(parent_author),
+                                Ok(_) => None,
+                                Err(why) => {
+                                    eprintln!("Error fetching parent of reply: {why}");
+                                    None
+                                },
+                            },
+                        }.filter(|parent_author| *parent_author != username && !mentioned.contains(parent_author));
+                        // Broadcast this message to all other subscribers, except for the author's own devices
+                        let targeted = serde_json::to_string(&mentioned.iter().chain(&parent_author).collect::<Vec<_>>()).unwrap();
+                        let subscriptions = query_as!(Subscription, "SELECT endpoint, p256dh, auth, username FROM subscription WHERE username != ? AND username NOT IN (SELECT value FROM json_each(?));", username, targeted).fetch_all(&pool).await;
+                        let mut batches = vec![Batch { subscriptions, notification: Notification { title, body: content.clone(), always_show: false, tag: Conversation::Room(room.clone()).tag(), link: link.clone() }, kind: PushKind::Chatter, reason: PushReason::Message { room: room.clone() }, coalesce: Some((Conversation::Room(room.clone()), username.clone())) }];
+                        if !mentioned.is_empty() {
+                            let mentioned = serde_json::to_string(&mentioned).unwrap();
+                            let subscriptions = query_as!(Subscription, "SELECT endpoint, p256dh, auth, username FROM subscription WHERE username IN (SELECT value FROM json_each(?));", mentioned).fetch_all(&pool).await;
+                            let title = if room == DEFAULT_ROOM { format!("{username} mentioned you") } else { format!("{username} mentioned you in #{room}") };
+                            batches.push(Batch { subscriptions, notification: Notification { title, body: content.clone(), always_show: true, tag: format!("mention:{seq}"), link: link.clone() }, kind: PushKind::Mention, reason: PushReason::Mention { room: room.clone() }, coalesce: None });
+                        }
+                        if let Some(parent_author) = parent_author {
+                            let subscriptions = query_as!(Subscription, "SELECT endpoint, p256dh, auth, username FROM subscription WHERE username = ?;", parent_author).fetch_all(&pool).await;
+                            batches.push(Batch { subscriptions, notification: Notification { title: format!("{username} replied to you"), body: content, always_show: true, tag: format!("reply:{seq}"), link }, kind: PushKind::Reply, reason: PushReason::Reply { room }, coalesce: None });
                         }
+                        batches
                     },
-                    _ => {}
+                    ChatMessage::DirectMessage { from, to, content, encrypted, .. } => {
+                        // Direct messages are private, so they only go to the recipient's subscriptions
+                        let subscriptions = query_as!(Subscription, "SELECT endpoint, p256dh, auth, username FROM subscription WHERE username = ?;", to).fetch_all(&pool).await;
+                        // We can't read encrypted messages, and the ciphertext would be useless in a notification
+                        let content = if encrypted { String::from("New encrypted message") } else { content };
+                        let conversation = Conversation::Direct(from.clone());
+                        let link = NotificationLink::new(id, None, &format!("@{from}"), None);
+                        let notification = Notification { title: format!("Direct message from {from}"), body: content, always_show: false, tag: conversation.tag(), link };
+                        vec![Batch { subscriptions, notification, kind: PushKind::Direct, reason: PushReason::Direct, coalesce: Some((conversation, from)) }]
+                    },
+                    // System messages are about the server, and only matter to whoever is connected right now
+                    ChatMessage::SystemMessage { .. } => continue,
+                    _ => continue,
+                };
+                // Each user's preferences are only looked up once per message
+                let mut preferences: HashMap<String, NotificationPreferences> = HashMap::new();
+                let now = history::now_millis();
+                for Batch { subscriptions, notification, kind, reason, coalesce } in batches {
+                    let params = config.params(kind);
+                    match subscriptions {
+                        Err(why) => eprintln!("Error fetching subscriptions: {why}"),
+                        Ok(subs) => {
+                            for sub in subs {
+                                if !preferences.contains_key(&sub.username) {
+                                    let user_preferences = match preferences::load_preferences(&pool, &sub.username).await {
+                                        Ok(user_preferences) => user_preferences,
+                                        Err(why) => {
+                                            eprintln!("Error fetching notification preferences of {}: {why}", sub.username);
+                                            NotificationPreferences::default()
+                                        },
+                                    };
+                                    preferences.insert(sub.username.clone(), user_preferences);
+                                }
+                                if !preferences::wants_push(&preferences[&sub.username], &reason, now, users.is_connected(&sub.username)) {
+                                    continue;
+                                }
+                                match &coalesce {
+                                    Some((conversation, sender)) => coalescer.push(sub, conversation.clone(), sender, notification.clone(), params.clone()),
+                                    // Notifications that are about the user in particular are never held back
+                                    None => {
+                                        tokio::spawn(send_with_retries(pool.clone(), sub, signer.clone(), client.clone(), notification.clone(), params.clone(), None));
+                                    },
+                                }
+                            }
+                        },
+                    }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Subscriptions that failed this many times in a row are reported in the log.
+const UNHEALTHY_AFTER_FAILURES: i64 = 5;
+/// How many times a push is attempted before giving up on it.
+const MAX_PUSH_ATTEMPTS: u32 = 4;
+/// How long to wait before the first retry. Every retry after that waits twice as long as the one before.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Push services can ask us to wait before retrying, but we won't hold on to a notification for longer than this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// What to do about a push that the push service didn't accept.
+enum PushFailure {
+    /// The subscription doesn't exist anymore, so it should be deleted.
+    Gone,
+    /// The push service is having problems or is rate limiting us, so the push may work if we try again later.
+    /// Contains how long the push service asked us to wait, if it did.
+    Transient(Option<Duration>),
+    /// Something is wrong with this push, so sending it again won't help.
+    Permanent,
+}
+
+impl From<&WebPushError> for PushFailure {
+    fn from(why: &WebPushError) -> Self {
+        match why {
+            WebPushError::EndpointNotFound | WebPushError::EndpointNotValid => PushFailure::Gone,
+            // `PushClient` also reports rate limiting (429) as a server error
+            WebPushError::ServerError(retry_after) => PushFailure::Transient(*retry_after),
+            WebPushError::IoError | WebPushError::TlsError | WebPushError::Unspecified => PushFailure::Transient(None),
+            _ => PushFailure::Permanent,
+        }
+    }
+}
+
+async fn send_push(sub: &Subscription, signer: &PartialVapidSignatureBuilder, client: &PushClient, notification: &Notification, params: &PushParams, topic: Option<&str>) -> Result<(), WebPushError> {
+    let info = SubscriptionInfo::new(&sub.endpoint, &sub.p256dh, &sub.auth);
+    let signer = signer.clone().add_sub_info(&info);
+    let mut builder = WebPushMessageBuilder::new(&info)?;
+    let content = serde_json::to_vec(notification).unwrap();
+    builder.set_payload(web_push::ContentEncoding::Aes128Gcm, &content);
+    builder.set_ttl(params.ttl);
+    builder.set_urgency(params.urgency.clone());
+    builder.set_vapid_signature(signer.build()?);
+
+    // Pushes with the same topic replace each other while they are waiting to be delivered
+    client.send(builder.build()?, topic).await
+}
+
+/// Send a push to one subscription, retrying with backoff while the push service has transient problems.
+/// Subscriptions that the push service says are gone are deleted.
+pub(crate) async fn send_with_retries(pool: SqlitePool, sub: Subscription, signer: PartialVapidSignatureBuilder, client: PushClient, notification: Notification, params: PushParams, topic: Option<String>) {
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=MAX_PUSH_ATTEMPTS {
+        let why = match send_push(&sub, &signer, &client, &notification, &params, topic.as_deref()).await {
+            Ok(()) => {
+                if let Err(why) = record_push_success(&pool, &sub.endpoint).await {
+                    eprintln!("Error recording push success for {}: {why}", sub.endpoint);
+                }
+                return;
+            },
+            Err(why) => why,
+        };
+        match PushFailure::from(&why) {
+            PushFailure::Gone => {
+                eprintln!("Subscription {} is gone ({why}), deleting it", sub.endpoint);
+                if let Err(why) = query!("DELETE FROM subscription WHERE endpoint = ?", sub.endpoint).execute(&pool).await {
+                    eprintln!("Error deleting subscription {}: {why}", sub.endpoint);
+                }
+                return;
+            },
+            PushFailure::Transient(retry_after) if attempt < MAX_PUSH_ATTEMPTS => {
+                eprintln!("Error sending to subscription {} (attempt {attempt}, will retry): {why}", sub.endpoint);
+                if let Err(why) = record_push_failure(&pool, &sub.endpoint, &why).await {
+                    eprintln!("Error recording push failure for {}: {why}", sub.endpoint);
+                }
+                tokio::time::sleep(retry_after.unwrap_or(delay).min(MAX_RETRY_DELAY)).await;
+                delay *= 2;
+            },
+            PushFailure::Transient(_) | PushFailure::Permanent => {
+                eprintln!("Error sending to subscription {}, giving up: {why}", sub.endpoint);
+                if let Err(why) = record_push_failure(&pool, &sub.endpoint, &why).await {
+                    eprintln!("Error recording push failure for {}: {why}", sub.endpoint);
+                }
+                return;
+            },
+        }
+    }
+}
+
+async fn record_push_success(pool: &SqlitePool, endpoint: &str) -> Result<(), sqlx::Error> {
+    let now = history::now_millis();
+    query!("UPDATE subscription SET consecutive_failures = 0, last_success_at = ? WHERE endpoint = ?", now, endpoint)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn record_push_failure(pool: &SqlitePool, endpoint: &str, why: &WebPushError) -> Result<(), sqlx::Error> {
+    let now = history::now_millis();
+    let why = why.to_string();
+    query!(
+        "UPDATE subscription SET failure_count = failure_count + 1, consecutive_failures = consecutive_failures + 1, last_failure_at = ?, last_error = ? WHERE endpoint = ?",
+        now,
+        why,
+        endpoint
+    )
+    .execute(pool)
+    .await?;
+    let sub = query!("SELECT consecutive_failures FROM subscription WHERE endpoint = ?", endpoint)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(sub) = sub {
+        if sub.consecutive_failures >= UNHEALTHY_AFTER_FAILURES {
+            eprintln!("Subscription {endpoint} is unhealthy: {} failures in a row", sub.consecutive_failures);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push_client::parse_response;
+
+    fn failure(status: u16, retry_after: Option<Duration>) -> PushFailure {
+        let status = StatusCode::from_u16(status).unwrap();
+        let why = parse_response(status, retry_after, Vec::new()).unwrap_err();
+        PushFailure::from(&why)
+    }
+
+    #[test]
+    fn missing_subscriptions_are_gone() {
+        assert!(matches!(failure(404, None), PushFailure::Gone));
+        assert!(matches!(failure(410, None), PushFailure::Gone));
+    }
+
+    #[test]
+    fn rate_limiting_is_transient() {
+        let retry_after = Some(Duration::from_secs(30));
+        assert!(matches!(failure(429, retry_after), PushFailure::Transient(after) if after == retry_after));
+        assert!(matches!(failure(429, None), PushFailure::Transient(None)));
+    }
+
+    #[test]
+    fn server_errors_are_transient() {
+        let retry_after = Some(Duration::from_secs(5));
+        assert!(matches!(failure(500, None), PushFailure::Transient(None)));
+        assert!(matches!(failure(503, retry_after), PushFailure::Transient(after) if after == retry_after));
+    }
+
+    #[test]
+    fn bad_pushes_are_permanent() {
+        assert!(matches!(failure(400, None), PushFailure::Permanent));
+        assert!(matches!(failure(413, None), PushFailure::Permanent));
+        assert!(matches!(failure(403, None), PushFailure::Permanent));
+    }
+
+    #[test]
+    fn accepted_pushes_are_not_failures() {
+        assert!(parse_response(StatusCode::CREATED, None, Vec::new()).is_ok());
+    }
+}
@@ -0,0 +1,330 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::Response,
+    Json,
+};
+use common::{NotificationLevel, NotificationPreferences, QuietHours};
+use serde::Deserialize;
+use sqlx::{query, SqlitePool};
+
+use crate::{
+    history::now_millis,
+    users::{self, SignatureCheck},
+    AppState,
+};
+
+/// Why a push notification is being sent to someone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushReason {
+    /// A message in a room, that isn't about them in particular.
+    Message { room: String },
+    /// A message in a room that mentions them.
+    Mention { room: String },
+    /// A reply to one of their messages.
+    Reply { room: String },
+    /// A direct message to them.
+    Direct,
+}
+
+/// Whether a user with these preferences wants a push notification.
+/// `now` is in milliseconds since the Unix epoch, and `connected` is whether they have an active socket.
+pub fn wants_push(
+    preferences: &NotificationPreferences,
+    reason: &PushReason,
+    now: i64,
+    connected: bool,
+) -> bool {
+    if preferences.skip_while_connected && connected {
+        return false;
+    }
+    if let Some(quiet_hours) = preferences.quiet_hours {
+        if quiet_hours.contains(now) {
+            return false;
+        }
+    }
+    match reason {
+        PushReason::Message { room } => {
+            preferences.level == NotificationLevel::All && !preferences.muted_rooms.contains(room)
+        }
+        // Muting a room doesn't hide things that are addressed to you
+        PushReason::Mention { .. } | PushReason::Reply { .. } | PushReason::Direct => true,
+    }
+}
+
+/// Look up a user's notification preferences, or the defaults if they never set any.
+pub async fn load_preferences(
+    pool: &SqlitePool,
+    username: &str,
+) -> anyhow::Result<NotificationPreferences> {
+    let row = query!(
+        r#"SELECT level, quiet_start, quiet_end, utc_offset, skip_while_connected AS "skip_while_connected: bool"
+        FROM notification_preferences WHERE username = ?"#,
+        username
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(NotificationPreferences::default());
+    };
+    let muted_rooms = query!(
+        "SELECT room FROM muted_room WHERE username = ? ORDER BY room",
+        username
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(NotificationPreferences {
+        level: match row.level.as_str() {
+            "mentions_and_direct" => NotificationLevel::MentionsAndDirect,
+            _ => NotificationLevel::All,
+        },
+        muted_rooms: muted_rooms.into_iter().map(|muted| muted.room).collect(),
+        quiet_hours: match (row.quiet_start, row.quiet_end) {
+            (Some(start), Some(end)) => Some(QuietHours {
+                start: start as u16,
+                end: end as u16,
+                utc_offset: row.utc_offset as i16,
+            }),
+            _ => None,
+        },
+        skip_while_connected: row.skip_while_connected,
+    })
+}
+
+async fn save_preferences(
+    pool: &SqlitePool,
+    username: &str,
+    preferences: &NotificationPreferences,
+) -> anyhow::Result<()> {
+    let level = preferences.level.as_str();
+    let quiet_start = preferences.quiet_hours.map(|quiet_hours| quiet_hours.start);
+    let quiet_end = preferences.quiet_hours.map(|quiet_hours| quiet_hours.end);
+    let utc_offset = preferences.quiet_hours.map_or(0, |quiet_hours| quiet_hours.utc_offset);
+    let mut tx = pool.begin().await?;
+    query!(
+        "INSERT INTO notification_preferences (username, level, quiet_start, quiet_end, utc_offset, skip_while_connected) VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (username) DO UPDATE SET level = excluded.level, quiet_start = excluded.quiet_start, quiet_end = excluded.quiet_end,
+            utc_offset = excluded.utc_offset, skip_while_connected = excluded.skip_while_connected",
+        username,
+        level,
+        quiet_start,
+        quiet_end,
+        utc_offset,
+        preferences.skip_while_connected
+    )
+    .execute(&mut tx)
+    .await?;
+    query!("DELETE FROM muted_room WHERE username = ?", username)
+        .execute(&mut tx)
+        .await?;
+    for room in &preferences.muted_rooms {
+        query!(
+            "INSERT OR IGNORE INTO muted_room (username, room) VALUES (?, ?)",
+            username,
+            room
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Minutes in a day, which quiet hours must start and end within.
+const MINUTES_PER_DAY: u16 = 24 * 60;
+/// Timezones are at most 14 hours away from UTC.
+const MAX_UTC_OFFSET: i16 = 14 * 60;
+
+/// How far the timestamp of a request about preferences may be from the server's clock, in milliseconds.
+const MAX_REQUEST_AGE: i64 = 5 * 60 * 1000;
+
+#[derive(Deserialize)]
+pub struct PreferencesParams {
+    username: String,
+    /// When the request was made, in milliseconds since the Unix epoch.
+    timestamp: i64,
+    /// Signature over `signing::read_notification_preferences_payload(username, timestamp)` by that user.
+    signature: Option<String>,
+}
+
+pub async fn get_preferences(
+    State(appstate): State<AppState>,
+    Query(params): Query<PreferencesParams>,
+) -> Response<String> {
+    async fn inner_get_preferences(
+        appstate: AppState,
+        params: PreferencesParams,
+    ) -> anyhow::Result<Response<String>> {
+        let pool = &appstate.pool;
+        let username = &params.username;
+        // Preferences say when someone is around and what they care about, so only they may read them.
+        let payload = common::signing::read_notification_preferences_payload(username, params.timestamp);
+        let check =
+            users::check_signature(pool, username, &payload, params.signature.as_deref()).await?;
+        if check != SignatureCheck::Valid {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(format!("could not prove that this request came from {username}"))
+                .unwrap());
+        }
+        // Without this, anyone who saw a signed request could keep sending it
+        if (now_millis() - params.timestamp).abs() > MAX_REQUEST_AGE {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body("this request is too old, check that your clock is right".to_string())
+                .unwrap());
+        }
+        let preferences = load_preferences(pool, username).await?;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&preferences).unwrap())
+            .unwrap())
+    }
+
+    match inner_get_preferences(appstate, params).await {
+        Ok(res) => res,
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PreferencesUpdate {
+    username: String,
+    preferences: NotificationPreferences,
+    /// When the request was made, in milliseconds since the Unix epoch.
+    timestamp: i64,
+    /// Signature over `signing::notification_preferences_payload(username, preferences, timestamp)` by that user.
+    signature: Option<String>,
+}
+
+pub async fn set_preferences(
+    State(appstate): State<AppState>,
+    Json(update): Json<PreferencesUpdate>,
+) -> Response<String> {
+    async fn inner_set_preferences(
+        appstate: AppState,
+        update: PreferencesUpdate,
+    ) -> anyhow::Result<Response<String>> {
+        let pool = &appstate.pool;
+        let username = &update.username;
+        let preferences = &update.preferences;
+        let payload =
+            common::signing::notification_preferences_payload(username, preferences, update.timestamp);
+        let check =
+            users::check_signature(pool, username, &payload, update.signature.as_deref()).await?;
+        if check != SignatureCheck::Valid {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(format!("could not prove that this request came from {username}"))
+                .unwrap());
+        }
+        // Otherwise an old update could be sent again to undo a newer one
+        if (now_millis() - update.timestamp).abs() > MAX_REQUEST_AGE {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body("this request is too old, check that your clock is right".to_string())
+                .unwrap());
+        }
+        if let Some(quiet_hours) = preferences.quiet_hours {
+            if quiet_hours.start >= MINUTES_PER_DAY
+                || quiet_hours.end >= MINUTES_PER_DAY
+                || quiet_hours.utc_offset.abs() > MAX_UTC_OFFSET
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("quiet hours must be times of day in a real timezone".to_string())
+                    .unwrap());
+            }
+        }
+        save_preferences(pool, username, preferences).await?;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(String::new())
+            .unwrap())
+    }
+
+    match inner_set_preferences(appstate, update).await {
+        Ok(res) => res,
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noon UTC on some day.
+    const NOON: i64 = 19_000 * 24 * 60 * 60 * 1000 + 12 * 60 * 60 * 1000;
+
+    fn message_in(room: &str) -> PushReason {
+        PushReason::Message {
+            room: room.to_string(),
+        }
+    }
+
+    #[test]
+    fn defaults_push_everything() {
+        let preferences = NotificationPreferences::default();
+        assert!(wants_push(&preferences, &message_in("general"), NOON, false));
+        assert!(wants_push(&preferences, &message_in("general"), NOON, true));
+        assert!(wants_push(&preferences, &PushReason::Direct, NOON, false));
+    }
+
+    #[test]
+    fn mentions_and_direct_skips_other_messages() {
+        let preferences = NotificationPreferences {
+            level: NotificationLevel::MentionsAndDirect,
+            ..Default::default()
+        };
+        let room = "general".to_string();
+        assert!(!wants_push(&preferences, &message_in("general"), NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Mention { room: room.clone() }, NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Reply { room }, NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Direct, NOON, false));
+    }
+
+    #[test]
+    fn muted_rooms_still_push_mentions() {
+        let preferences = NotificationPreferences {
+            muted_rooms: vec!["random".to_string()],
+            ..Default::default()
+        };
+        let room = "random".to_string();
+        assert!(!wants_push(&preferences, &message_in("random"), NOON, false));
+        assert!(wants_push(&preferences, &message_in("general"), NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Mention { room: room.clone() }, NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Reply { room }, NOON, false));
+    }
+
+    #[test]
+    fn quiet_hours_push_nothing() {
+        let preferences = NotificationPreferences {
+            quiet_hours: Some(QuietHours {
+                start: 11 * 60,
+                end: 13 * 60,
+                utc_offset: 0,
+            }),
+            ..Default::default()
+        };
+        assert!(!wants_push(&preferences, &message_in("general"), NOON, false));
+        assert!(!wants_push(&preferences, &PushReason::Direct, NOON, false));
+        assert!(wants_push(&preferences, &PushReason::Direct, NOON + 2 * 60 * 60 * 1000, false));
+    }
+
+    #[test]
+    fn skip_while_connected() {
+        let preferences = NotificationPreferences {
+            skip_while_connected: true,
+            ..Default::default()
+        };
+        assert!(!wants_push(&preferences, &PushReason::Direct, NOON, true));
+        assert!(wants_push(&preferences, &PushReason::Direct, NOON, false));
+    }
+}
@@ -0,0 +1,69 @@
+//! Sending Web Push messages.
+//!
+//! The client that comes with web-push can't set the `Topic` header, which lets a newer push replace
+//! an older one that the push service hasn't delivered yet. So the request is built with web-push,
+//! the header is added to it, and it is sent here instead.
+
+use std::time::Duration;
+
+use isahc::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    AsyncBody, AsyncReadResponseExt, HttpClient,
+};
+use web_push::{request_builder, WebPushError, WebPushMessage};
+
+/// Cheap to clone: clones share the same connections.
+#[derive(Clone)]
+pub struct PushClient {
+    client: HttpClient,
+}
+
+impl PushClient {
+    pub fn new() -> Result<Self, WebPushError> {
+        Ok(PushClient {
+            client: HttpClient::new()?,
+        })
+    }
+
+    /// Send a push. Pushes to the same subscription with the same `topic` replace each other
+    /// while they are waiting to be delivered. Topics can be at most 32 characters from the URL-safe base64 alphabet.
+    pub async fn send(&self, message: WebPushMessage, topic: Option<&str>) -> Result<(), WebPushError> {
+        let mut request = request_builder::build_request::<AsyncBody>(message);
+        if let Some(topic) = topic {
+            let value = HeaderValue::from_str(topic)
+                .map_err(|_| WebPushError::Other(format!("invalid topic {topic:?}")))?;
+            request.headers_mut().insert("Topic", value);
+        }
+        let mut response = self.client.send_async(request).await?;
+        // Only the number of seconds form of the header is understood, not HTTP dates
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs);
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|_| WebPushError::InvalidResponse)?;
+        parse_response(status, retry_after, body)
+    }
+}
+
+/// Turn the push service's response into the result of the push.
+pub(crate) fn parse_response(
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: Vec<u8>,
+) -> Result<(), WebPushError> {
+    // web-push doesn't know about rate limiting, and would report it as an unexpected status.
+    // It's temporary like a server error, and usually comes with a Retry-After too.
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(WebPushError::ServerError(retry_after));
+    }
+    match request_builder::parse_response(status, body) {
+        Err(WebPushError::ServerError(None)) => Err(WebPushError::ServerError(retry_after)),
+        result => result,
+    }
+}
@@ -0,0 +1,118 @@
+//! Which Web Push parameters each kind of notification is sent with.
+//!
+//! Each kind can be configured in the `.env` file with `PUSH_<KIND>_TTL` (in seconds)
+//! and `PUSH_<KIND>_URGENCY` (`very-low`, `low`, `normal` or `high`), for example `PUSH_CHATTER_TTL=600`.
+
+use std::env;
+
+use anyhow::{anyhow, Context};
+use web_push::Urgency;
+
+const MINUTE: u32 = 60;
+const HOUR: u32 = 60 * MINUTE;
+const DAY: u32 = 24 * HOUR;
+
+/// The kinds of push notifications that can have different parameters.
+/// System messages aren't here because they are never pushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushKind {
+    Direct,
+    Mention,
+    Reply,
+    /// Messages in a room that aren't about the user in particular.
+    Chatter,
+    /// The notification that is sent when a subscription is registered.
+    Test,
+}
+
+impl PushKind {
+    fn env_name(self) -> &'static str {
+        match self {
+            PushKind::Direct => "DIRECT",
+            PushKind::Mention => "MENTION",
+            PushKind::Reply => "REPLY",
+            PushKind::Chatter => "CHATTER",
+            PushKind::Test => "TEST",
+        }
+    }
+}
+
+/// How long the push service should keep trying to deliver a push, and how soon the device should wake up for it.
+#[derive(Clone)]
+pub struct PushParams {
+    /// In seconds.
+    pub ttl: u32,
+    pub urgency: Urgency,
+}
+
+#[derive(Clone)]
+pub struct PushConfig {
+    direct: PushParams,
+    mention: PushParams,
+    reply: PushParams,
+    chatter: PushParams,
+    test: PushParams,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            // Things that are addressed to the user are worth waking their device up for, even if it's a day late
+            direct: PushParams { ttl: DAY, urgency: Urgency::High },
+            mention: PushParams { ttl: DAY, urgency: Urgency::High },
+            reply: PushParams { ttl: 12 * HOUR, urgency: Urgency::Normal },
+            // Chatter is only interesting while it's happening
+            chatter: PushParams { ttl: 15 * MINUTE, urgency: Urgency::Low },
+            test: PushParams { ttl: 5 * MINUTE, urgency: Urgency::High },
+        }
+    }
+}
+
+fn parse_urgency(value: &str) -> anyhow::Result<Urgency> {
+    match value {
+        "very-low" => Ok(Urgency::VeryLow),
+        "low" => Ok(Urgency::Low),
+        "normal" => Ok(Urgency::Normal),
+        "high" => Ok(Urgency::High),
+        _ => Err(anyhow!("unknown urgency {value:?}, expected very-low, low, normal or high")),
+    }
+}
+
+impl PushConfig {
+    /// The defaults, with whatever is set in the environment overriding them.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = PushConfig::default();
+        for kind in [PushKind::Direct, PushKind::Mention, PushKind::Reply, PushKind::Chatter, PushKind::Test] {
+            let params = config.params_mut(kind);
+            let ttl_var = format!("PUSH_{}_TTL", kind.env_name());
+            if let Ok(ttl) = env::var(&ttl_var) {
+                params.ttl = ttl.trim().parse().with_context(|| format!("{ttl_var} should be a number of seconds"))?;
+            }
+            let urgency_var = format!("PUSH_{}_URGENCY", kind.env_name());
+            if let Ok(urgency) = env::var(&urgency_var) {
+                params.urgency = parse_urgency(urgency.trim()).with_context(|| format!("invalid {urgency_var}"))?;
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn params(&self, kind: PushKind) -> &PushParams {
+        match kind {
+            PushKind::Direct => &self.direct,
+            PushKind::Mention => &self.mention,
+            PushKind::Reply => &self.reply,
+            PushKind::Chatter => &self.chatter,
+            PushKind::Test => &self.test,
+        }
+    }
+
+    fn params_mut(&mut self, kind: PushKind) -> &mut PushParams {
+        match kind {
+            PushKind::Direct => &mut self.direct,
+            PushKind::Mention => &mut self.mention,
+            PushKind::Reply => &mut self.reply,
+            PushKind::Chatter => &mut self.chatter,
+            PushKind::Test => &mut self.test,
+        }
+    }
+}
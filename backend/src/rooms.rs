@@ -0,0 +1,86 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use sqlx::{query, SqlitePool};
+
+use crate::{history::now_millis, AppState};
+
+pub fn get_rooms_router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_rooms))
+        .route("/:name", post(create_room))
+}
+
+/// Room names are shown in the UI and used in URLs, so keep them simple.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub async fn room_exists(pool: &SqlitePool, name: &str) -> Result<bool, sqlx::Error> {
+    let room = query!("SELECT name FROM room WHERE name=?", name)
+        .fetch_optional(pool)
+        .await?;
+    Ok(room.is_some())
+}
+
+async fn list_rooms(State(appstate): State<AppState>) -> Response<String> {
+    let rooms = query!("SELECT name FROM room ORDER BY name")
+        .fetch_all(&appstate.pool)
+        .await;
+    match rooms {
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+        Ok(rooms) => {
+            let names = rooms.into_iter().map(|room| room.name).collect::<Vec<_>>();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&names).unwrap())
+                .unwrap()
+        }
+    }
+}
+
+async fn create_room(
+    State(appstate): State<AppState>,
+    Path(name): Path<String>,
+) -> Response<String> {
+    if !is_valid_room_name(&name) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("room names must be 1-64 letters, digits, '-' or '_'".to_string())
+            .unwrap();
+    }
+    let created_at = now_millis();
+    let result = query!(
+        "INSERT INTO room (name, created_at) VALUES (?, ?) ON CONFLICT (name) DO NOTHING",
+        name,
+        created_at
+    )
+    .execute(&appstate.pool)
+    .await;
+    match result {
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+        Ok(result) if result.rows_affected() == 0 => Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body("a room with this name already exists".to_string())
+            .unwrap(),
+        Ok(_) => Response::builder()
+            .status(StatusCode::CREATED)
+            .body("created room".to_string())
+            .unwrap(),
+    }
+}
@@ -0,0 +1,179 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use common::{
+    ChatMessage, SearchResult, Seq, ServerMessage, DEFAULT_ROOM, HIGHLIGHT_END, HIGHLIGHT_START,
+};
+use serde::Deserialize;
+use sqlx::{query, SqlitePool};
+
+use crate::AppState;
+
+/// The most results that `GET /search` will return at once.
+const MAX_RESULTS: i64 = 100;
+
+/// Turn the user's search text into an FTS5 query that matches messages containing all of the words.
+///
+/// Every word is quoted, so that characters with a special meaning in FTS5 syntax
+/// (like `"`, `*`, `-` or `:`) are searched for literally instead of causing a syntax error.
+fn to_fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub async fn search_messages(
+    pool: &SqlitePool,
+    text: &str,
+    username: Option<&str>,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: i64,
+) -> anyhow::Result<Vec<SearchResult>> {
+    let fts_query = to_fts_query(text);
+    let highlight_start = HIGHLIGHT_START.to_string();
+    let highlight_end = HIGHLIGHT_END.to_string();
+    let rows = query!(
+        r#"SELECT message.id AS "id!", message.seq AS "seq!", message.created_at AS "created_at!", message.edited_at, message.room, message.username,
+            message.content AS "content!", message.signature, message.reply_to,
+            snippet(message_fts, 1, ?, ?, '...', 16) AS "snippet!: String"
+        FROM message_fts JOIN message ON message.id = message_fts.rowid
+        WHERE message_fts MATCH ? AND message.deleted_at IS NULL
+            AND (? IS NULL OR message.username = ?)
+            AND (? IS NULL OR message.created_at >= ?)
+            AND (? IS NULL OR message.created_at < ?)
+        ORDER BY message.id DESC
+        LIMIT ?"#,
+        highlight_start,
+        highlight_end,
+        fts_query,
+        username,
+        username,
+        from,
+        from,
+        to,
+        to,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| SearchResult {
+            message: ServerMessage {
+                id: Some(row.id),
+                seq: row.seq as Seq,
+                timestamp: row.created_at,
+                edited_at: row.edited_at,
+                reactions: Vec::new(),
+                reply_count: 0,
+                message: ChatMessage::TextMessage {
+                    room: row.room.unwrap_or_else(|| DEFAULT_ROOM.to_string()),
+                    username: row.username.unwrap_or_default(),
+                    content: row.content,
+                    signature: row.signature,
+                    reply_to: row.reply_to,
+                },
+            },
+            snippet: row.snippet,
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    username: Option<String>,
+    /// Only find messages sent at or after this time (milliseconds since the Unix epoch).
+    from: Option<i64>,
+    /// Only find messages sent before this time (milliseconds since the Unix epoch).
+    to: Option<i64>,
+    limit: Option<i64>,
+}
+
+pub async fn get_search(
+    State(appstate): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> Response<String> {
+    if params.q.trim().is_empty() {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("search text must not be empty".to_string())
+            .unwrap();
+    }
+    let limit = params.limit.unwrap_or(MAX_RESULTS).clamp(1, MAX_RESULTS);
+    let result = search_messages(
+        &appstate.pool,
+        &params.q,
+        params.username.as_deref().filter(|name| !name.is_empty()),
+        params.from,
+        params.to,
+        limit,
+    )
+    .await;
+    match result {
+        Err(why) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("database error: {why}"))
+            .unwrap(),
+        Ok(results) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&results).unwrap())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_quoted() {
+        assert_eq!(to_fts_query("hello"), r#""hello""#);
+        assert_eq!(to_fts_query("  hello   world "), r#""hello" "world""#);
+        assert_eq!(to_fts_query(""), "");
+    }
+
+    #[test]
+    fn quotes_are_escaped() {
+        assert_eq!(to_fts_query(r#"say "hi""#), r#""say" """hi""""#);
+    }
+
+    #[test]
+    fn operators_are_searched_for_literally() {
+        assert_eq!(to_fts_query("cats OR dogs"), r#""cats" "OR" "dogs""#);
+        assert_eq!(to_fts_query("NOT -x col:y pre*"), r#""NOT" "-x" "col:y" "pre*""#);
+        assert_eq!(to_fts_query("NEAR(a b)"), r#""NEAR(a" "b)""#);
+    }
+
+    #[tokio::test]
+    async fn queries_are_valid_fts5() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE VIRTUAL TABLE t USING fts5(content)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO t (content) VALUES ('cats OR dogs'), ('say \"hi\"')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let searches = [
+            ("cats OR dogs", 1),
+            ("OR", 1),
+            (r#""hi"#, 1),
+            ("NOT -x col:y pre* (", 0),
+        ];
+        for (text, matches) in searches {
+            let found: Vec<(String,)> = sqlx::query_as("SELECT content FROM t WHERE t MATCH ?")
+                .bind(to_fts_query(text))
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(found.len(), matches, "{text}");
+        }
+    }
+}
@@ -0,0 +1,687 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use base64::Engine;
+use common::{ChatMessage, MessageId, Seq, ServerMessage, DEFAULT_ROOM};
+use rand::{seq::SliceRandom, RngCore, SeedableRng};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, Notify,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    deletion::{self, DeletePermission},
+    history,
+    message_manager::{ConnectionId, Outgoing, UserConnection, RECONNECT_GRACE_PERIOD},
+    rooms,
+    users::{self, SignatureCheck},
+    AppState,
+};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The longest reaction that is accepted, in bytes.
+/// Emoji made of several code points (like flags or families) can be quite long, but not longer than this.
+const MAX_EMOJI_LEN: usize = 32;
+
+#[derive(Deserialize)]
+pub struct WebsocketParams {
+    /// The ID of the last message that the client has seen.
+    /// If given, the client gets every message after it; otherwise, it gets the most recent ones.
+    since: Option<MessageId>,
+}
+
+pub async fn handle_websocket_connection(
+    State(appstate): State<AppState>,
+    Query(params): Query<WebsocketParams>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |ws| handle_socket(ws, appstate, params.since))
+}
+
+/// Serialize a message to send it to the client.
+fn ws_text(message: &ServerMessage) -> Message {
+    Message::Text(serde_json::to_string(message).unwrap())
+}
+
+/// Serialize a system message that is only meant for this client.
+fn ws_notice(content: String) -> Message {
+    ws_text(&history::ephemeral(ChatMessage::SystemMessage {
+        room: None,
+        content,
+    }))
+}
+
+/// Which of a room's messages to send when joining it.
+enum Backfill {
+    /// Just the most recent messages.
+    Recent,
+    /// Everything after the message with this ID.
+    AfterId(MessageId),
+    /// Everything after this sequence number, for clients resuming after a reconnect.
+    AfterSeq(Seq),
+}
+
+/// The state of a single websocket connection.
+struct Connection {
+    id: ConnectionId,
+    appstate: AppState,
+    name: String,
+    nonce: String,
+    authenticated: bool,
+    /// The rooms that this connection is in, with the tasks that forward each room's messages.
+    rooms: HashMap<String, JoinHandle<()>>,
+    outgoing_tx: mpsc::Sender<Outgoing>,
+    /// Notified when the connection falls too far behind on its direct messages.
+    kick: Arc<Notify>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for (_, task) in self.rooms.drain() {
+            task.abort();
+        }
+        if self.authenticated {
+            self.appstate.users.unregister(&self.name, self.id);
+        }
+    }
+}
+
+/// Forward the messages of one room to the connection, skipping the ones that were already sent as history.
+///
+/// If the connection falls so far behind that the room's broadcaster drops messages before it gets them,
+/// everything in the room that changed since the last forwarded sequence number is loaded from the history instead.
+/// Only a closed broadcaster ends forwarding.
+fn forward_room(
+    pool: SqlitePool,
+    room: String,
+    mut receiver: broadcast::Receiver<ServerMessage>,
+    outgoing_tx: mpsc::Sender<Outgoing>,
+    mut last_sent_id: MessageId,
+    mut last_sent_seq: Seq,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let msg = match receiver.recv().await {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(missed)) => {
+                    // The receiver now starts at the oldest message still buffered,
+                    // so new messages that we load from the history here get skipped when they come through the broadcaster.
+                    // Edits, deletions and reactions may be sent twice, which clients handle.
+                    let resync = history::fetch_after_seq(&pool, &room, last_sent_seq, history::MAX_BACKFILL).await;
+                    let missed_msgs = match resync {
+                        Ok(msgs) if (msgs.len() as i64) < history::MAX_BACKFILL => msgs,
+                        Ok(msgs) => {
+                            // Too much to catch up on; send what we have and tell the client that there's a gap.
+                            let notice = history::ephemeral(ChatMessage::SystemMessage {
+                                room: Some(room.clone()),
+                                content: format!("Your connection fell too far behind in #{room} to catch up on everything; reload to see the rest"),
+                            });
+                            if outgoing_tx.send(Ok(notice)).await.is_err() {
+                                return;
+                            }
+                            msgs
+                        }
+                        Err(why) => {
+                            eprintln!("Error loading history to resync lagging connection: {why}");
+                            vec![history::ephemeral(ChatMessage::SystemMessage {
+                                room: Some(room.clone()),
+                                content: format!("You missed {missed} messages in #{room} while your connection was slow"),
+                            })]
+                        }
+                    };
+                    for msg in missed_msgs {
+                        last_sent_id = msg.id.map_or(last_sent_id, |id| id.max(last_sent_id));
+                        last_sent_seq = last_sent_seq.max(msg.seq);
+                        if outgoing_tx.send(Ok(msg)).await.is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    #[allow(unused_must_use)]
+                    {
+                        outgoing_tx.send(Err(RecvError::Closed)).await;
+                    }
+                    return;
+                }
+            };
+            if let Some(id) = msg.id {
+                if id <= last_sent_id {
+                    // Already sent as part of the history
+                    continue;
+                }
+                last_sent_id = id;
+            }
+            last_sent_seq = last_sent_seq.max(msg.seq);
+            if outgoing_tx.send(Ok(msg)).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+impl Connection {
+    /// Start receiving a room's messages, after sending its history.
+    /// Messages that are shown in every room may be sent again for every room; clients recognize those by sequence number.
+    async fn join_room(
+        &mut self,
+        socket: &mut WebSocket,
+        room: &str,
+        backfill: Backfill,
+    ) -> anyhow::Result<()> {
+        if self.rooms.contains_key(room) {
+            // A resuming client may have missed messages in a room that it was put in when it connected.
+            // Some of these may also come through the forwarder, but clients replace messages they already have.
+            if let Backfill::AfterSeq(since) = backfill {
+                for msg in history::fetch_after_seq(&self.appstate.pool, room, since, history::MAX_BACKFILL).await? {
+                    socket.send(ws_text(&msg)).await?;
+                }
+            }
+            return Ok(());
+        }
+        if !rooms::room_exists(&self.appstate.pool, room).await? {
+            socket
+                .send(ws_notice(format!("Room {room} does not exist")))
+                .await?;
+            return Ok(());
+        }
+
+        // Where to resync from if the connection falls behind before anything newer is sent.
+        let mut last_sent_seq = history::last_seq();
+        // Subscribe before the history is loaded, so that no message can fall in between the two.
+        let receiver = self.appstate.rooms.subscribe(room);
+        let pool = &self.appstate.pool;
+        let mut last_sent_id = 0;
+        let backfill = match backfill {
+            Backfill::Recent => history::fetch_recent(pool, room, history::DEFAULT_BACKFILL).await,
+            Backfill::AfterId(since) => {
+                last_sent_id = since;
+                history::fetch_since(pool, room, since, history::MAX_BACKFILL).await
+            }
+            Backfill::AfterSeq(since) => history::fetch_after_seq(pool, room, since, history::MAX_BACKFILL).await,
+        };
+        match backfill {
+            Err(why) => eprintln!("Error loading message history: {why}"),
+            Ok(backfill) => {
+                for msg in backfill {
+                    // Resuming clients also get older messages that changed, which mustn't move this back.
+                    last_sent_id = msg.id.map_or(last_sent_id, |id| id.max(last_sent_id));
+                    last_sent_seq = last_sent_seq.max(msg.seq);
+                    socket.send(ws_text(&msg)).await?;
+                }
+            }
+        }
+        self.rooms.insert(
+            room.to_string(),
+            forward_room(
+                self.appstate.pool.clone(),
+                room.to_string(),
+                receiver,
+                self.outgoing_tx.clone(),
+                last_sent_id,
+                last_sent_seq,
+            ),
+        );
+        Ok(())
+    }
+
+    /// Bind the connection to a registered user, after they've proven that they own the username.
+    ///
+    /// Returns whether the user was already connected, or is coming back within the grace period,
+    /// in which case there's no need to tell everyone that they connected.
+    async fn authenticate(
+        &mut self,
+        socket: &mut WebSocket,
+        username: String,
+        last_seen: Option<Seq>,
+    ) -> anyhow::Result<bool> {
+        let users = &self.appstate.users;
+        if self.authenticated {
+            users.unregister(&self.name, self.id);
+        }
+        let returning = users.is_returning(&username);
+        self.name = username;
+        self.authenticated = true;
+        users.register(
+            &self.name,
+            self.id,
+            UserConnection {
+                sender: self.outgoing_tx.clone(),
+                kick: self.kick.clone(),
+            },
+        );
+
+        // Catch the user up on their direct messages
+        let pool = &self.appstate.pool;
+        let backfill = match last_seen {
+            Some(since) => history::fetch_direct_after_seq(pool, &self.name, since, history::MAX_BACKFILL).await,
+            None => history::fetch_direct_recent(pool, &self.name, history::DEFAULT_BACKFILL).await,
+        };
+        match backfill {
+            Err(why) => eprintln!("Error loading direct message history: {why}"),
+            Ok(backfill) => {
+                for msg in backfill {
+                    socket.send(ws_text(&msg)).await?;
+                }
+            }
+        }
+        Ok(returning)
+    }
+
+    fn leave_room(&mut self, room: &str) {
+        if let Some(task) = self.rooms.remove(room) {
+            task.abort();
+        }
+    }
+
+    async fn process_incoming_msg(
+        &mut self,
+        socket: &mut WebSocket,
+        data: &str,
+    ) -> anyhow::Result<()> {
+        // Try to parse the message as a ChatMessage struct.
+        // If we fail, send this message as an anonymous message with no signature to the default room.
+        let msg = match serde_json::from_str(data) {
+            Ok(msg) => msg,
+            Err(_) => ChatMessage::TextMessage {
+                room: DEFAULT_ROOM.to_string(),
+                username: self.name.clone(),
+                content: data.to_string(),
+                signature: None,
+                reply_to: None,
+            },
+        };
+        let pool = &self.appstate.pool;
+        let message_sender = &self.appstate.message_manager_tx;
+
+        // Replies must be to a text message in the same room, so that threads don't leak between rooms.
+        // Threads are only one level deep, so replies can't be replied to.
+        if let ChatMessage::TextMessage {
+            room,
+            reply_to: Some(parent),
+            ..
+        } = &msg
+        {
+            let parent_ok = match history::fetch_message(pool, *parent).await?.map(|msg| msg.message) {
+                Some(ChatMessage::TextMessage {
+                    room: parent_room,
+                    reply_to: None,
+                    ..
+                }) => parent_room == *room,
+                _ => false,
+            };
+            if !parent_ok {
+                socket
+                    .send(ws_notice(format!(
+                        "Message was not sent: there is no message {parent} in #{room} to reply to"
+                    )))
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        match msg {
+            ChatMessage::TextMessage { ref room, .. } if !self.rooms.contains_key(room) => {
+                socket
+                    .send(ws_notice(format!(
+                        "Message was not sent: you need to join room {room} first"
+                    )))
+                    .await?
+            }
+            // Messages that claim to be from a registered user must be signed by that user's key,
+            // otherwise anyone could impersonate them just by typing their name.
+            // They must also come from a connection authenticated as that user,
+            // since anyone who saw a signed message could send it again.
+            ChatMessage::TextMessage {
+                ref room,
+                ref username,
+                ref content,
+                ref signature,
+                reply_to,
+            } => {
+                let payload = common::signing::text_message_payload(room, username, content, reply_to);
+                let check =
+                    users::check_signature(pool, username, &payload, signature.as_deref()).await?;
+                let authenticated = self.authenticated && *username == self.name;
+                let problem = match check {
+                    SignatureCheck::UnregisteredUser => None,
+                    SignatureCheck::Valid if authenticated => None,
+                    SignatureCheck::Valid => Some("you are not authenticated as them"),
+                    SignatureCheck::Missing => Some("the signature was missing"),
+                    SignatureCheck::Invalid => Some("the signature was invalid"),
+                };
+                match problem {
+                    None => message_sender.send(msg).await?,
+                    Some(problem) => {
+                        socket
+                            .send(ws_notice(format!(
+                                "Message was not sent: {username} is a registered user, and {problem}"
+                            )))
+                            .await?
+                    }
+                }
+            }
+            ChatMessage::SystemMessage { .. } => {
+                socket
+                    .send(ws_notice(format!("Cannot send system messages")))
+                    .await?
+            }
+            ChatMessage::AuthChallenge { .. } => {
+                socket
+                    .send(ws_notice(format!("Only the server can send challenges")))
+                    .await?
+            }
+            ChatMessage::ConnectionUsername {
+                username,
+                signature,
+                last_seen,
+            } => {
+                let payload = common::signing::challenge_payload(&username, &self.nonce);
+                let check =
+                    users::check_signature(pool, &username, &payload, signature.as_deref()).await?;
+                if check == SignatureCheck::Valid {
+                    let returning = self.authenticate(socket, username.clone(), last_seen).await?;
+                    if !returning {
+                        let message_sender = &self.appstate.message_manager_tx;
+                        message_sender
+                            .send(ChatMessage::SystemMessage {
+                                room: None,
+                                content: format!("{username} connected to chat"),
+                            })
+                            .await?;
+                    }
+                } else {
+                    let reason = match check {
+                        SignatureCheck::UnregisteredUser => "this username is not registered",
+                        SignatureCheck::Missing => "the challenge was not signed",
+                        _ => "the challenge signature is invalid",
+                    };
+                    socket
+                        .send(ws_notice(format!(
+                            "Could not authenticate as {username} ({reason}), you are still {}",
+                            self.name
+                        )))
+                        .await?
+                }
+            }
+            ChatMessage::JoinRoom { room, since } => {
+                let backfill = since.map_or(Backfill::Recent, Backfill::AfterSeq);
+                self.join_room(socket, &room, backfill).await?
+            }
+            ChatMessage::LeaveRoom { room } => self.leave_room(&room),
+            ChatMessage::DirectMessage { ref from, ref to, .. } => {
+                // Direct messages are only between registered users, so the sender must always prove who they are.
+                // Like with text messages, a signature alone could be replayed, so the connection must be authenticated.
+                if !self.authenticated || *from != self.name {
+                    socket
+                        .send(ws_notice(format!(
+                            "Direct message was not sent: you need to be authenticated as {from}"
+                        )))
+                        .await?
+                } else if users::find_public_key_jwk(pool, to).await?.is_none() {
+                    socket
+                        .send(ws_notice(format!(
+                            "Direct message was not sent: {to} is not a registered user"
+                        )))
+                        .await?
+                } else {
+                    message_sender.send(msg).await?
+                }
+            }
+            ChatMessage::EditMessage {
+                id,
+                ref new_content,
+                ref signature,
+            } => {
+                let author = match history::fetch_message(pool, id).await?.map(|msg| msg.message) {
+                    Some(ChatMessage::TextMessage { username, .. }) => Some(username),
+                    Some(ChatMessage::DirectMessage { from, .. }) => Some(from),
+                    _ => None,
+                };
+                let Some(author) = author else {
+                    socket
+                        .send(ws_notice(format!("Message {id} does not exist, or can't be edited")))
+                        .await?;
+                    return Ok(());
+                };
+                // Edits must come from a connection authenticated as the author, so that a signed edit can't be replayed
+                // to undo a later one. They are also always signed with the author's key, so that other clients can check them too.
+                let payload = common::signing::edit_message_payload(id, new_content);
+                let check = users::check_signature(pool, &author, &payload, signature.as_deref()).await?;
+                if self.authenticated && author == self.name && check == SignatureCheck::Valid {
+                    message_sender.send(msg).await?
+                } else {
+                    socket
+                        .send(ws_notice(format!(
+                            "Message {id} was not edited: only {author} can edit it, while authenticated and signing the edit with their key"
+                        )))
+                        .await?
+                }
+            }
+            ChatMessage::DeleteMessage { id } => {
+                // Authenticating the connection is how the user proves who they are here;
+                // unauthenticated clients can sign a request to `DELETE /messages/:id` instead.
+                if !self.authenticated {
+                    socket
+                        .send(ws_notice(format!("Message {id} was not deleted: you need to be authenticated")))
+                        .await?;
+                    return Ok(());
+                }
+                match deletion::check_delete_permission(pool, id, &self.name).await? {
+                    DeletePermission::Allowed => message_sender.send(msg).await?,
+                    DeletePermission::NotFound => {
+                        socket
+                            .send(ws_notice(format!("Message {id} does not exist, or can't be deleted")))
+                            .await?
+                    }
+                    DeletePermission::Forbidden => {
+                        socket
+                            .send(ws_notice(format!(
+                                "Message {id} was not deleted: only its author or a moderator can delete it"
+                            )))
+                            .await?
+                    }
+                }
+            }
+            ChatMessage::Reaction {
+                message_id,
+                ref emoji,
+                add,
+                ..
+            } => {
+                if !self.authenticated {
+                    socket
+                        .send(ws_notice("You need to be authenticated to react to messages".to_string()))
+                        .await?;
+                    return Ok(());
+                }
+                if emoji.trim().is_empty() || emoji.len() > MAX_EMOJI_LEN {
+                    socket
+                        .send(ws_notice("Reactions must be a single emoji".to_string()))
+                        .await?;
+                    return Ok(());
+                }
+                // Direct messages can only be reacted to by the people in the conversation.
+                let visible = match history::fetch_message(pool, message_id).await?.map(|msg| msg.message) {
+                    Some(ChatMessage::TextMessage { .. }) => true,
+                    Some(ChatMessage::DirectMessage { from, to, .. }) => from == self.name || to == self.name,
+                    _ => false,
+                };
+                if !visible {
+                    socket
+                        .send(ws_notice(format!("Message {message_id} does not exist, or can't be reacted to")))
+                        .await?;
+                    return Ok(());
+                }
+                message_sender
+                    .send(ChatMessage::Reaction {
+                        message_id,
+                        emoji: emoji.clone(),
+                        add,
+                        username: self.name.clone(),
+                    })
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn announce_disconnect(&self) {
+        let message_sender = self.appstate.message_manager_tx.clone();
+        let message = ChatMessage::SystemMessage {
+            room: None,
+            content: format!("{} disconnected from chat", self.name),
+        };
+        if !self.authenticated {
+            #[allow(unused_must_use)]
+            {
+                message_sender.send(message).await;
+            }
+            return;
+        }
+
+        // Registered users often come right back (like after a network hiccup),
+        // so only say that they left if they stay away for the whole grace period.
+        let users = self.appstate.users.clone();
+        let username = self.name.clone();
+        let left_at = users.mark_left(&username);
+        tokio::spawn(async move {
+            tokio::time::sleep(RECONNECT_GRACE_PERIOD).await;
+            if users.has_left(&username, left_at) {
+                #[allow(unused_must_use)]
+                {
+                    message_sender.send(message).await;
+                }
+            }
+        });
+    }
+}
+
+async fn handle_socket(mut socket: WebSocket, appstate: AppState, since: Option<MessageId>) {
+    // Generate a username to use for simple messages
+
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut name = String::from("Anonymous User ");
+    let letters = [
+        '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
+    ];
+    for _ in 0..4 {
+        let letter = letters.choose(&mut rng).unwrap();
+        name.push(*letter);
+    }
+
+    // Send a challenge, which the client needs to sign in order to be known by a registered username.
+    // Until then, the connection stays anonymous.
+    let mut nonce_bytes = [0u8; 32];
+    rng.fill_bytes(&mut nonce_bytes);
+    let nonce = base64::engine::general_purpose::STANDARD.encode(nonce_bytes);
+    if socket
+        .send(ws_text(&history::ephemeral(ChatMessage::AuthChallenge {
+            nonce: nonce.clone(),
+        })))
+        .await
+        .is_err()
+    {
+        // Client disconnected before it could even see the challenge
+        return;
+    }
+
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel(100);
+    let mut connection = Connection {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        appstate,
+        name,
+        nonce,
+        authenticated: false,
+        rooms: HashMap::new(),
+        outgoing_tx,
+        kick: Arc::new(Notify::new()),
+    };
+    let kick = connection.kick.clone();
+
+    // Every connection starts out in the default room, and gets its history so that the client isn't looking at an empty screen.
+    if connection
+        .join_room(&mut socket, DEFAULT_ROOM, since.map_or(Backfill::Recent, Backfill::AfterId))
+        .await
+        .is_err()
+    {
+        // Client disconnected while receiving the history
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            maybe_client_msg = socket.recv() => {
+                match maybe_client_msg {
+                    Some(Ok(Message::Text(data))) => {
+                        match connection.process_incoming_msg(&mut socket, &data).await {
+                            Ok(_) => {},
+                            Err(why) => {eprintln!("Error while processing client message (are we shutting down?): {why}")},
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => {
+                        // client disconnected
+                        connection.announce_disconnect().await;
+                        return;
+                    }
+                }
+            }
+
+            _ = kick.notified() => {
+                // The client reconnects and catches up from the last message it saw.
+                #[allow(unused_must_use)]
+                {
+                socket.send(Message::Close(Some(CloseFrame{ code: close_code::AGAIN, reason: Cow::from("Too far behind, please reconnect") }))).await;
+                socket.close();
+                }
+                connection.announce_disconnect().await;
+                return;
+            }
+
+            maybe_server_msg = outgoing_rx.recv() => {
+                match maybe_server_msg {
+                    // The connection always holds a sender, so the channel can't be closed.
+                    None => unreachable!(),
+                    // Lagging behind is handled by the room forwarders, so this only happens if the broadcaster is gone.
+                    Some(Err(_)) => {
+                        #[allow(unused_must_use)]
+                        {
+                        socket.send(Message::Close(Some(CloseFrame{ code: close_code::ABNORMAL, reason: Cow::from("Error while retreiving other members' messages (maybe server going down?)") }))).await;
+                        socket.close();
+                        }
+                        connection.announce_disconnect().await;
+                        return;
+                    },
+                    Some(Ok(msg)) => {
+                        if socket.send(ws_text(&msg)).await.is_err() {
+                            // Probably client disconnected?
+                            connection.announce_disconnect().await;
+                            return;
+                        }
+                    },
+                }
+            }
+        };
+    }
+}
@@ -0,0 +1,82 @@
+use k256::PublicKey;
+use sqlx::{query, SqlitePool};
+
+/// Look up the JWK public key that a registered user has published.
+pub async fn find_public_key_jwk(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let maybe_existing_user = query!("SELECT * FROM user WHERE name=?", username)
+        .fetch_optional(pool)
+        .await?;
+    Ok(maybe_existing_user.map(|user| user.public_key))
+}
+
+/// Look up and parse the public key of a registered user.
+pub async fn find_public_key(
+    pool: &SqlitePool,
+    username: &str,
+) -> anyhow::Result<Option<PublicKey>> {
+    match find_public_key_jwk(pool, username).await? {
+        None => Ok(None),
+        Some(jwk) => Ok(Some(PublicKey::from_jwk_str(&jwk)?)),
+    }
+}
+
+/// Whether the user is a moderator, who can delete anyone's messages.
+pub async fn is_moderator(pool: &SqlitePool, username: &str) -> anyhow::Result<bool> {
+    let user = query!(
+        r#"SELECT is_moderator AS "is_moderator: bool" FROM user WHERE name = ?"#,
+        username
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(user.is_some_and(|user| user.is_moderator))
+}
+
+/// Of the given usernames, the ones that belong to registered users.
+pub async fn registered_usernames(
+    pool: &SqlitePool,
+    usernames: &[&str],
+) -> anyhow::Result<Vec<String>> {
+    let usernames = serde_json::to_string(usernames)?;
+    let users = query!(
+        "SELECT name FROM user WHERE name IN (SELECT value FROM json_each(?))",
+        usernames
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(users.into_iter().map(|user| user.name).collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureCheck {
+    /// The username belongs to a registered user, and the signature was made with their key.
+    Valid,
+    /// Nobody registered this username, so there is no key to check against.
+    UnregisteredUser,
+    /// The username belongs to a registered user, but there is no signature.
+    Missing,
+    /// The username belongs to a registered user, but the signature does not match their key.
+    Invalid,
+}
+
+/// Check that `payload` was signed by the registered owner of `username`.
+pub async fn check_signature(
+    pool: &SqlitePool,
+    username: &str,
+    payload: &[u8],
+    signature: Option<&str>,
+) -> anyhow::Result<SignatureCheck> {
+    let Some(key) = find_public_key(pool, username).await? else {
+        return Ok(SignatureCheck::UnregisteredUser);
+    };
+    let Some(signature) = signature else {
+        return Ok(SignatureCheck::Missing);
+    };
+    if common::signing::verify(&key, payload, signature) {
+        Ok(SignatureCheck::Valid)
+    } else {
+        Ok(SignatureCheck::Invalid)
+    }
+}
@@ -1,20 +1,359 @@
 use serde::{Deserialize, Serialize};
 
+pub mod mentions;
+pub mod signing;
+
+/// ID that the server assigns to every message it stores in the history.
+/// IDs only ever increase, so they can also be used to order messages.
+pub type MessageId = i64;
+
+/// Sequence number that the server assigns to every message it emits, stored or not.
+/// Sequence numbers only ever increase, even across server restarts.
+pub type Seq = u64;
+
+/// The room that every connection is in when it starts, and which always exists.
+pub const DEFAULT_ROOM: &str = "general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+/// Every message that the server sends to a client is wrapped in this.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ServerMessage {
+    /// `None` for messages that are not part of the history,
+    /// like errors that are only sent to one client.
+    pub id: Option<MessageId>,
+    /// Unique for every message the server emits.
+    /// Stored messages keep theirs when they are replayed from the history.
+    pub seq: Seq,
+    /// When the server received the message, in milliseconds since the Unix epoch (UTC).
+    pub timestamp: i64,
+    /// When the message was last edited, if it ever was.
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+    /// The reactions to the message, in the order they were first used.
+    #[serde(default)]
+    pub reactions: Vec<ReactionSummary>,
+    /// How many replies there are to the message.
+    #[serde(default)]
+    pub reply_count: u32,
+    pub message: ChatMessage,
+}
+
+/// Everyone who reacted to a message with one emoji.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    /// The users who reacted with this emoji, in the order they did.
+    pub users: Vec<String>,
+}
+
+impl ServerMessage {
+    /// Wrap a message that is only meant for one client and is not stored.
+    pub fn ephemeral(seq: Seq, timestamp: i64, message: ChatMessage) -> Self {
+        ServerMessage {
+            id: None,
+            seq,
+            timestamp,
+            edited_at: None,
+            reactions: Vec::new(),
+            reply_count: 0,
+            message,
+        }
+    }
+}
+
+/// A page of the message history, as returned by `GET /messages`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    /// Messages in this page, oldest first.
+    pub messages: Vec<ServerMessage>,
+    /// Pass this as `before` to get the next (older) page.
+    /// `None` if there are no older messages.
+    pub next_before: Option<MessageId>,
+}
+
+/// A message and its replies, as returned by `GET /messages/:id/thread`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Thread {
+    pub parent: ServerMessage,
+    /// Replies to the parent, oldest first.
+    pub replies: Vec<ServerMessage>,
+}
+
+/// An earlier version of an edited message, as returned by `GET /messages/:id/revisions`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub content: String,
+    pub signature: Option<String>,
+    /// When this version was written, in milliseconds since the Unix epoch (UTC).
+    pub timestamp: i64,
+}
+
+/// Marks the start of a matched term in `SearchResult::snippet`.
+pub const HIGHLIGHT_START: char = '\u{2}';
+/// Marks the end of a matched term in `SearchResult::snippet`.
+pub const HIGHLIGHT_END: char = '\u{3}';
+
+/// A message that matched a query to `GET /search`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub message: ServerMessage,
+    /// The part of the message around the match.
+    /// Matched terms are surrounded by `HIGHLIGHT_START` and `HIGHLIGHT_END`.
+    pub snippet: String,
+}
+
+/// Which messages a user wants push notifications about.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationLevel {
+    /// Every message in the rooms they haven't muted.
+    #[default]
+    All,
+    /// Only messages that mention them, replies to them, and direct messages.
+    MentionsAndDirect,
+}
+
+impl NotificationLevel {
+    /// The name of the level, as it is stored and signed.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::MentionsAndDirect => "mentions_and_direct",
+        }
+    }
+}
+
+/// A time of day during which a user doesn't want push notifications.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// When quiet hours start, in minutes after midnight in the user's timezone.
+    pub start: u16,
+    /// When quiet hours end, in minutes after midnight in the user's timezone.
+    /// If this is before `start`, quiet hours go past midnight.
+    pub end: u16,
+    /// The user's timezone, as minutes ahead of UTC.
+    pub utc_offset: i16,
+}
+
+impl QuietHours {
+    const MINUTES_PER_DAY: i64 = 24 * 60;
+
+    /// Whether the time, in milliseconds since the Unix epoch, is during quiet hours.
+    pub fn contains(&self, millis: i64) -> bool {
+        let minute = (millis / 60_000 + self.utc_offset as i64).rem_euclid(Self::MINUTES_PER_DAY);
+        let (start, end) = (self.start as i64, self.end as i64);
+        if start <= end {
+            start <= minute && minute < end
+        } else {
+            start <= minute || minute < end
+        }
+    }
+}
+
+/// What a user wants push notifications about, from `/notification/preferences`.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    pub level: NotificationLevel,
+    /// Rooms that they don't want to hear about, unless they are mentioned or replied to.
+    #[serde(default)]
+    pub muted_rooms: Vec<String>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// If true, nothing is pushed to them while they are connected to the chat, since they can already see it.
+    #[serde(default)]
+    pub skip_while_connected: bool,
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum ChatMessage {
     TextMessage {
+        /// The room this message was sent to.
+        /// Clients that don't know about rooms send to the default room.
+        #[serde(default = "default_room")]
+        room: String,
         username: String,
         content: String,
+        /// Signature over `signing::text_message_payload(room, username, content, reply_to)`.
+        /// Required if the username belongs to a registered user, and then the server also only accepts the message
+        /// from a connection authenticated as them, since a signature on its own could be replayed.
         signature: Option<String>,
+        /// The message that this is a reply to, which must be a text message in the same room.
+        /// Replies are shown in the parent's thread instead of the room.
+        #[serde(default)]
+        reply_to: Option<MessageId>,
     },
     SystemMessage {
+        /// The room this message is about, or `None` if it is shown in every room.
+        #[serde(default)]
+        room: Option<String>,
         content: String,
     },
 
+    /// This message is sent by the server as soon as the client connects.
+    /// To authenticate as a registered user, the client must sign the nonce with its key
+    /// and send the signature back in `ConnectionUsername`.
+    AuthChallenge {
+        nonce: String,
+    },
+
     /// This message is sent by the client sometime at the start of the conversation
     /// so that the server knows what user the connection is associated with.
     /// When the server receives this, it will also emit a message saying that this client is connected.
+    ///
+    /// The connection is only bound to the username if it belongs to a registered user
+    /// and the signature over `signing::challenge_payload(username, nonce)` checks out.
     ConnectionUsername {
         username: String,
+        signature: Option<String>,
+        /// When reconnecting, the sequence number of the last message the client saw.
+        /// The server replays the direct messages after it, instead of just the recent ones.
+        #[serde(default)]
+        last_seen: Option<Seq>,
+    },
+
+    /// Sent by the client to start receiving messages from a room.
+    /// The server replies with the room's recent history.
+    /// Every connection starts out in `DEFAULT_ROOM`.
+    JoinRoom {
+        room: String,
+        /// When reconnecting, the sequence number of the last message the client saw.
+        /// The server replays every message in the room after it, even if the connection is already in the room.
+        #[serde(default)]
+        since: Option<Seq>,
+    },
+
+    /// Sent by the client to stop receiving messages from a room.
+    LeaveRoom {
+        room: String,
+    },
+
+    /// A private message between two registered users.
+    /// The server only delivers it to connections authenticated as the sender or the recipient.
+    DirectMessage {
+        from: String,
+        to: String,
+        /// The message text, or if `encrypted` is set, the base64 nonce and ciphertext.
+        content: String,
+        /// Signature over `signing::direct_message_payload(from, to, content)`.
+        /// The server only accepts the message from a connection authenticated as `from`.
+        signature: Option<String>,
+        /// Whether `content` is end-to-end encrypted with a key derived from the two users' keys.
+        /// The server can route and store these messages, but not read them.
+        #[serde(default)]
+        encrypted: bool,
+    },
+
+    /// Sent by the author of a text or direct message to change what it says.
+    /// The server stores the earlier version, and sends this to everyone who could see the message,
+    /// so that they can update it.
+    EditMessage {
+        id: MessageId,
+        /// For encrypted direct messages, this is encrypted like the original.
+        new_content: String,
+        /// Signature over `signing::edit_message_payload(id, new_content)` by the author of the message.
+        /// Always required, so that everyone can check that the edit came from the author.
+        /// The server also only accepts edits from a connection authenticated as the author.
+        signature: Option<String>,
+    },
+
+    /// Take back a text or direct message. The server removes its content,
+    /// and sends this to everyone who could see the message, so that they can hide it.
+    ///
+    /// Over the websocket, the connection must be authenticated as the author of the message or as a moderator.
+    /// The same can be done with `DELETE /messages/:id`.
+    DeleteMessage {
+        id: MessageId,
+    },
+
+    /// Add or remove a reaction to a text or direct message.
+    /// Each user can react to a message with each emoji once.
+    /// The server sends this to everyone who could see the message, so that they can update its reactions.
+    Reaction {
+        message_id: MessageId,
+        emoji: String,
+        /// Whether the reaction is being added or removed.
+        add: bool,
+        /// The user who reacted. The server fills this in from the authenticated connection,
+        /// so anything the client puts here is ignored.
+        #[serde(default)]
+        username: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Milliseconds since the Unix epoch at a time of day (UTC) on some day.
+    fn at(hour: i64, minute: i64) -> i64 {
+        const SOME_DAY: i64 = 19_000 * 24 * 60 * 60 * 1000;
+        SOME_DAY + (hour * 60 + minute) * 60_000
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet_hours = QuietHours {
+            start: 9 * 60,
+            end: 17 * 60,
+            utc_offset: 0,
+        };
+        assert!(!quiet_hours.contains(at(8, 59)));
+        assert!(quiet_hours.contains(at(9, 0)));
+        assert!(quiet_hours.contains(at(16, 59)));
+        assert!(!quiet_hours.contains(at(17, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_wrap_around_midnight() {
+        let quiet_hours = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset: 0,
+        };
+        assert!(!quiet_hours.contains(at(21, 59)));
+        assert!(quiet_hours.contains(at(22, 0)));
+        assert!(quiet_hours.contains(at(0, 0)));
+        assert!(quiet_hours.contains(at(6, 59)));
+        assert!(!quiet_hours.contains(at(7, 0)));
+        assert!(!quiet_hours.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_use_the_users_timezone() {
+        // 22:00 to 07:00 at UTC+2 is 20:00 to 05:00 UTC
+        let ahead = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset: 2 * 60,
+        };
+        assert!(!ahead.contains(at(19, 59)));
+        assert!(ahead.contains(at(20, 0)));
+        assert!(ahead.contains(at(4, 59)));
+        assert!(!ahead.contains(at(5, 0)));
+
+        // 22:00 to 07:00 at UTC-5 is 03:00 to 12:00 UTC
+        let behind = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset: -5 * 60,
+        };
+        assert!(!behind.contains(at(2, 59)));
+        assert!(behind.contains(at(3, 0)));
+        assert!(behind.contains(at(11, 59)));
+        assert!(!behind.contains(at(12, 0)));
+        assert!(!behind.contains(at(22, 0)));
+    }
+
+    #[test]
+    fn empty_quiet_hours_are_never_quiet() {
+        let quiet_hours = QuietHours {
+            start: 8 * 60,
+            end: 8 * 60,
+            utc_offset: 0,
+        };
+        assert!(!quiet_hours.contains(at(8, 0)));
     }
 }
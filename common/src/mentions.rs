@@ -0,0 +1,91 @@
+//! Finding `@username` mentions in message content.
+//!
+//! The backend uses this to decide who to notify, and the frontend to highlight mentions,
+//! so that both agree on what counts as a mention.
+
+use std::ops::Range;
+
+/// Characters that can be part of a mentioned username.
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Find the mentions in a message, as byte ranges that include the `@`.
+///
+/// A mention must start at the beginning of the content or after a character that can't be in a name,
+/// so that email addresses don't count. Dots at the end are left out, because they usually end a sentence.
+pub fn find_mentions(content: &str) -> Vec<Range<usize>> {
+    let mut mentions = Vec::new();
+    let mut previous = None;
+    for (start, c) in content.char_indices() {
+        let at_boundary = previous.is_none_or(|previous| !is_name_char(previous));
+        previous = Some(c);
+        if c != '@' || !at_boundary {
+            continue;
+        }
+        let name_start = start + 1;
+        let name_len = content[name_start..]
+            .find(|c| !is_name_char(c))
+            .unwrap_or(content.len() - name_start);
+        let name = content[name_start..name_start + name_len].trim_end_matches('.');
+        if !name.is_empty() {
+            mentions.push(start..name_start + name.len());
+        }
+    }
+    mentions
+}
+
+/// The usernames mentioned in a message, without duplicates, in the order they first appear.
+pub fn mentioned_usernames(content: &str) -> Vec<&str> {
+    let mut usernames = Vec::new();
+    for mention in find_mentions(content) {
+        let username = &content[mention.start + 1..mention.end];
+        if !usernames.contains(&username) {
+            usernames.push(username);
+        }
+    }
+    usernames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mentions(content: &str) -> Vec<&str> {
+        find_mentions(content)
+            .into_iter()
+            .map(|mention| &content[mention])
+            .collect()
+    }
+
+    #[test]
+    fn mentions_start_at_word_boundaries() {
+        assert_eq!(mentions("@alice hi"), ["@alice"]);
+        assert_eq!(mentions("hi @alice and @bob"), ["@alice", "@bob"]);
+        assert_eq!(mentions("(@alice)"), ["@alice"]);
+        assert_eq!(mentions("mail alice@example.com"), Vec::<&str>::new());
+        assert_eq!(mentions("user_1@host"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn trailing_punctuation_is_left_out() {
+        assert_eq!(mentions("thanks @alice."), ["@alice"]);
+        assert_eq!(mentions("thanks @alice..."), ["@alice"]);
+        assert_eq!(mentions("@alice, @bob!"), ["@alice", "@bob"]);
+        assert_eq!(mentions("@alice? @bob:"), ["@alice", "@bob"]);
+        // Dots and dashes inside a name are part of it
+        assert_eq!(mentions("@first.last-name"), ["@first.last-name"]);
+    }
+
+    #[test]
+    fn a_lone_at_sign_is_not_a_mention() {
+        assert_eq!(mentions("@"), Vec::<&str>::new());
+        assert_eq!(mentions("look @ this"), Vec::<&str>::new());
+        assert_eq!(mentions("@."), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn mentioned_usernames_are_deduplicated_in_order() {
+        assert_eq!(mentioned_usernames("@bob @alice @bob."), ["bob", "alice"]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Helpers for signing and verifying messages with the users' k256 keys.
+//!
+//! Both the frontend and the backend use these, so that they always agree on
+//! what bytes are actually being signed.
+
+use base64::Engine;
+use k256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    PublicKey, SecretKey,
+};
+
+use crate::{MessageId, NotificationPreferences, QuietHours};
+
+/// Build the bytes that are signed for a `ChatMessage::TextMessage`.
+///
+/// The room and username are length-prefixed, so that moving characters
+/// between the fields changes the payload.
+/// Messages that aren't replies have the same payload as before replies existed, so their old signatures still check out.
+pub fn text_message_payload(
+    room: &str,
+    username: &str,
+    content: &str,
+    reply_to: Option<MessageId>,
+) -> Vec<u8> {
+    let header = match reply_to {
+        None => "TextMessage".to_string(),
+        Some(parent) => format!("TextMessage reply_to {parent}"),
+    };
+    format!(
+        "{header}\n{}\n{room}\n{}\n{username}\n{content}",
+        room.len(),
+        username.len()
+    )
+    .into_bytes()
+}
+
+/// Build the bytes that are signed for a `ChatMessage::DirectMessage`.
+pub fn direct_message_payload(from: &str, to: &str, content: &str) -> Vec<u8> {
+    format!(
+        "DirectMessage\n{}\n{from}\n{}\n{to}\n{content}",
+        from.len(),
+        to.len()
+    )
+    .into_bytes()
+}
+
+/// Build the bytes that are signed for a `ChatMessage::EditMessage`.
+/// After an edit, this is also what the stored signature of the message is over.
+pub fn edit_message_payload(id: MessageId, new_content: &str) -> Vec<u8> {
+    format!("EditMessage\n{id}\n{new_content}").into_bytes()
+}
+
+/// Build the bytes that are signed to delete a message through `DELETE /messages/:id`.
+pub fn delete_message_payload(id: MessageId) -> Vec<u8> {
+    format!("DeleteMessage\n{id}").into_bytes()
+}
+
+/// Build the bytes that are signed to tie a Web Push subscription to a user.
+pub fn push_subscription_payload(username: &str, endpoint: &str) -> Vec<u8> {
+    format!("PushSubscription\n{}\n{username}\n{endpoint}", username.len()).into_bytes()
+}
+
+/// Build the bytes that are signed to change a user's notification preferences.
+/// `timestamp` is when the request was made, in milliseconds since the Unix epoch, so that old updates can't be sent again.
+pub fn notification_preferences_payload(
+    username: &str,
+    preferences: &NotificationPreferences,
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut payload = format!(
+        "NotificationPreferences\n{}\n{username}\n{timestamp}\n{}\n{}\n",
+        username.len(),
+        preferences.level.as_str(),
+        preferences.skip_while_connected
+    );
+    match preferences.quiet_hours {
+        None => payload.push_str("no quiet hours\n"),
+        Some(QuietHours {
+            start,
+            end,
+            utc_offset,
+        }) => payload.push_str(&format!("quiet hours {start} {end} {utc_offset}\n")),
+    }
+    for room in &preferences.muted_rooms {
+        payload.push_str(&format!("{}\n{room}\n", room.len()));
+    }
+    payload.into_bytes()
+}
+
+/// Build the bytes that are signed to read a user's notification preferences.
+/// `timestamp` is when the request was made, in milliseconds since the Unix epoch, so that old requests can't be sent again.
+pub fn read_notification_preferences_payload(username: &str, timestamp: i64) -> Vec<u8> {
+    format!(
+        "ReadNotificationPreferences\n{}\n{username}\n{timestamp}",
+        username.len()
+    )
+    .into_bytes()
+}
+
+/// Build the bytes that are signed in response to a `ChatMessage::AuthChallenge`.
+pub fn challenge_payload(username: &str, nonce: &str) -> Vec<u8> {
+    format!("AuthChallenge\n{}\n{username}\n{nonce}", username.len()).into_bytes()
+}
+
+/// Sign the payload, returning the signature as a base64 string.
+pub fn sign(key: &SecretKey, payload: &[u8]) -> String {
+    let signing_key = SigningKey::from(key);
+    let signature: Signature = signing_key.sign(payload);
+    base64::engine::general_purpose::STANDARD.encode(signature.to_bytes())
+}
+
+/// Check that the base64 `signature` was made over `payload` by the owner of `key`.
+pub fn verify(key: &PublicKey, payload: &[u8], signature: &str) -> bool {
+    let Ok(signature_bytes) = base64::engine::general_purpose::STANDARD.decode(signature) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature_bytes) else {
+        return false;
+    };
+    VerifyingKey::from(key).verify(payload, &signature).is_ok()
+}
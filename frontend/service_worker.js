@@ -64,9 +64,43 @@ self.addEventListener('push', function(event) {
       }
     
       // Client isn't focused, we need to show a notification.
-        return self.registration.showNotification(event.data.json().title, {
-          body: event.data.json().body
-        }).then(function(){console.log("Received push ",event.data.json())})
+      // Notifications with the same tag are about the same conversation, so a newer one replaces the older one,
+      // and renotify makes sure the user still hears about it.
+        const data = event.data.json();
+        return self.registration.showNotification(data.title, {
+          body: data.body,
+          tag: data.tag,
+          renotify: Boolean(data.tag),
+          // Kept so that clicking the notification can open the message
+          data: data,
+        }).then(function(){console.log("Received push ",data)})
+      })
+  );
+});
+
+// Clicking a notification opens the app at the message it is about.
+// If the app is already open, that window is focused and told to show the message, instead of being reloaded.
+self.addEventListener('notificationclick', function(event) {
+  event.notification.close();
+  const data = event.notification.data || {};
+  const url = new URL(data.url || '/', self.location.origin).href;
+  event.waitUntil(
+    // Only windows that we control are running the app, and can receive our messages.
+    clients
+      .matchAll({
+        type: 'window',
+      })
+      .then(function(windowClients) {
+        const windowClient = windowClients.find(function(client) { return client.focused; }) || windowClients[0];
+        if (!windowClient) {
+          return clients.openWindow(url);
+        }
+        // Focusing has to happen right away, while we are still handling the click.
+        return windowClient.focus().then(function(focusedClient) {
+          (focusedClient || windowClient).postMessage({ type: 'open-link', url: url });
+        }).catch(function() {
+          return clients.openWindow(url);
+        });
       })
   );
 });
@@ -1,12 +1,77 @@
-use common::ChatMessage;
-use wasm_bindgen::JsCast;
+use std::collections::HashMap;
+
+use common::{
+    ChatMessage, HistoryPage, MessageId, MessageRevision, ReactionSummary, ServerMessage, Thread,
+    DEFAULT_ROOM,
+};
+use k256::SecretKey;
+use wasm_bindgen::prelude::{wasm_bindgen, Closure};
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen::UnwrapThrowExt;
-use web_sys::HtmlInputElement;
+use web_sys::{HtmlInputElement, UrlSearchParams};
 use yew::prelude::*;
 use yew_hooks::prelude::*;
 
+use crate::encryption;
+use crate::pubkeys::{request_pubkey, KeyLookup, Verification};
+use crate::rooms::RoomSwitcher;
+use crate::search::SearchBox;
 use crate::web_push::WebPushSetup;
 
+#[wasm_bindgen]
+extern "C" {
+    fn set_open_link_handler(handler: JsValue);
+}
+
+/// Where a link from a push notification points.
+struct NotificationLink {
+    room: Option<String>,
+    thread: Option<MessageId>,
+    message: Option<MessageId>,
+}
+
+impl NotificationLink {
+    /// Parse the query string of a link: `?room=...&message=...`, and `&thread=...` for replies.
+    fn parse(search: &str) -> Option<Self> {
+        let params = UrlSearchParams::new_with_str(search).ok()?;
+        Some(NotificationLink {
+            room: params.get("room"),
+            thread: params.get("thread").and_then(|id| id.parse().ok()),
+            message: params.get("message").and_then(|id| id.parse().ok()),
+        })
+    }
+}
+
+/// The chat window either shows a room, or a direct conversation with another user.
+/// Room names can't contain `@`, so conversations are stored as `@username`.
+fn direct_peer(view: &str) -> Option<&str> {
+    view.strip_prefix('@')
+}
+
+/// The other user in a direct message conversation.
+fn conversation_peer<'a>(from: &'a str, to: &'a str, me: &str) -> &'a str {
+    if from == me {
+        to
+    } else {
+        from
+    }
+}
+
+/// Whether a message should be shown while looking at a room or conversation.
+/// Replies are only shown in their thread.
+fn is_in_view(message: &ChatMessage, view: &str, me: &str) -> bool {
+    match message {
+        ChatMessage::TextMessage { room, reply_to, .. } => room == view && reply_to.is_none(),
+        ChatMessage::SystemMessage { room, .. } => {
+            room.as_deref().is_none_or(|room| room == view)
+        }
+        ChatMessage::DirectMessage { from, to, .. } => {
+            direct_peer(view) == Some(conversation_peer(from, to, me))
+        }
+        _ => true,
+    }
+}
+
 #[function_component]
 pub fn ChatWindow() -> Html {
     let loc = &use_location();
@@ -16,22 +81,149 @@ pub fn ChatWindow() -> Html {
         loc.host,
     );
 
-    let chat_history: UseListHandle<ChatMessage> = use_list(vec![]);
+    let chat_history: UseListHandle<ServerMessage> = use_list(vec![]);
     let did_send_username = use_state_eq(|| false);
+    let auth_challenge = use_state_eq(|| None::<String>);
+    // Keys of the users we've seen messages from, so that we can check signatures ourselves
+    // instead of trusting the server about who sent what.
+    let pubkeys: UseMapHandle<String, KeyLookup> = use_map(HashMap::new());
 
     let username = use_local_storage::<String>("username".to_string());
+    let privkey = use_local_storage::<String>("private_key".to_string());
+    let me = (*username)
+        .clone()
+        .expect_throw("no username while in chat window code?!");
+    let stored_room = use_local_storage::<String>("current_room".to_string());
+    let current_room = (*stored_room)
+        .clone()
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
 
     let options = UseWebSocketOptions {
         onopen: None,
         onmessage: Some({
             let chat_history = chat_history.clone();
+            let auth_challenge = auth_challenge.clone();
+            let pubkeys = pubkeys.clone();
+            let origin = loc.origin.clone();
             Box::new(move |message| {
-                let message_parsed = serde_json::from_str(&message);
+                let message_parsed: Result<ServerMessage, _> = serde_json::from_str(&message);
                 match message_parsed {
-                    Ok(msg) => chat_history.push(msg),
-                    Err(why) => chat_history.push(ChatMessage::SystemMessage {
-                        content: format!("Server sent an unexpected message: {why}"),
-                    }),
+                    Ok(ServerMessage {
+                        message: ChatMessage::AuthChallenge { nonce },
+                        ..
+                    }) => auth_challenge.set(Some(nonce)),
+                    // Deleted messages are hidden right away.
+                    Ok(ServerMessage {
+                        message: ChatMessage::DeleteMessage { id },
+                        ..
+                    }) => {
+                        let index = chat_history.current().iter().position(|seen| seen.id == Some(id));
+                        if let Some(index) = index {
+                            chat_history.remove(index);
+                        }
+                    }
+                    Ok(ServerMessage {
+                        message:
+                            ChatMessage::Reaction {
+                                message_id,
+                                emoji,
+                                add,
+                                username,
+                            },
+                        ..
+                    }) => {
+                        let index = chat_history.current().iter().position(|seen| seen.id == Some(message_id));
+                        if let Some(index) = index {
+                            let mut reacted = chat_history.current()[index].clone();
+                            let reactions = &mut reacted.reactions;
+                            let summary = reactions.iter().position(|summary| summary.emoji == emoji);
+                            match (summary, add) {
+                                (Some(summary), true) => {
+                                    if !reactions[summary].users.contains(&username) {
+                                        reactions[summary].users.push(username);
+                                    }
+                                }
+                                (None, true) => reactions.push(ReactionSummary {
+                                    emoji,
+                                    users: vec![username],
+                                }),
+                                (Some(summary), false) => {
+                                    reactions[summary].users.retain(|user| *user != username);
+                                    if reactions[summary].users.is_empty() {
+                                        reactions.remove(summary);
+                                    }
+                                }
+                                (None, false) => {}
+                            }
+                            chat_history.update(index, reacted);
+                        }
+                    }
+                    // Edits aren't shown by themselves, they change the message they are about.
+                    Ok(ServerMessage {
+                        message:
+                            ChatMessage::EditMessage {
+                                id,
+                                new_content,
+                                signature: new_signature,
+                            },
+                        edited_at,
+                        ..
+                    }) => {
+                        let index = chat_history.current().iter().position(|seen| seen.id == Some(id));
+                        if let Some(index) = index {
+                            let mut edited = chat_history.current()[index].clone();
+                            match &mut edited.message {
+                                ChatMessage::TextMessage { content, signature, .. }
+                                | ChatMessage::DirectMessage { content, signature, .. } => {
+                                    *content = new_content;
+                                    *signature = new_signature;
+                                }
+                                _ => {}
+                            }
+                            edited.edited_at = edited_at;
+                            chat_history.update(index, edited);
+                        }
+                    }
+                    Ok(msg) => {
+                        // After a reconnect, the server replays history that we may already have,
+                        // including messages that were edited or reacted to while we were gone, so replace what we have.
+                        let index = chat_history
+                            .current()
+                            .iter()
+                            .position(|seen| seen.seq == msg.seq || (msg.id.is_some() && seen.id == msg.id));
+                        if let Some(index) = index {
+                            chat_history.update(index, msg);
+                            return;
+                        }
+                        // A new reply makes the parent's reply count go up.
+                        if let ChatMessage::TextMessage { reply_to: Some(parent), .. } = &msg.message {
+                            let index = chat_history.current().iter().position(|seen| seen.id == Some(*parent));
+                            if let Some(index) = index {
+                                let mut replied = chat_history.current()[index].clone();
+                                replied.reply_count += 1;
+                                chat_history.update(index, replied);
+                            }
+                        }
+                        match &msg.message {
+                            ChatMessage::TextMessage { username, .. } => request_pubkey(&pubkeys, &origin, username),
+                            ChatMessage::DirectMessage { from, to, .. } => {
+                                // We need the other user's key to decrypt, even for messages we sent.
+                                request_pubkey(&pubkeys, &origin, from);
+                                request_pubkey(&pubkeys, &origin, to);
+                            }
+                            _ => {}
+                        }
+                        chat_history.push(msg)
+                    }
+                    // This didn't come from the server, so it has no sequence number.
+                    Err(why) => chat_history.push(ServerMessage::ephemeral(
+                        0,
+                        js_sys::Date::now() as i64,
+                        ChatMessage::SystemMessage {
+                            room: None,
+                            content: format!("Server sent an unexpected message: {why}"),
+                        },
+                    )),
                 }
             })
         }),
@@ -45,6 +237,180 @@ pub fn ChatWindow() -> Html {
     };
     let ws_conn = use_websocket_with_options(path, options);
 
+    // Whether the server may have older messages in this room than the ones we're showing.
+    let has_older = use_state_eq(|| true);
+    let load_older = {
+        let chat_history = chat_history.clone();
+        let pubkeys = pubkeys.clone();
+        let has_older = has_older.clone();
+        let origin = loc.origin.clone();
+        let current_room = current_room.clone();
+        let me = me.clone();
+        use_async(async move {
+            let oldest = chat_history
+                .current()
+                .iter()
+                .filter(|msg| is_in_view(&msg.message, &current_room, &me))
+                .find_map(|msg| msg.id);
+            let mut url = format!("{origin}/messages?room={current_room}");
+            if let Some(oldest) = oldest {
+                url.push_str(&format!("&before={oldest}"));
+            }
+            let page: HistoryPage = reqwest::get(url)
+                .await
+                .map_err(|why| format!("Error fetching older messages: {why}"))?
+                .json()
+                .await
+                .map_err(|why| format!("Error reading older messages: {why}"))?;
+            has_older.set(page.next_before.is_some());
+            // Insert the page before the oldest message we have in this room, skipping anything that's already there.
+            let mut index = chat_history
+                .current()
+                .iter()
+                .position(|msg| msg.id.is_some() && msg.id == oldest)
+                .unwrap_or(0);
+            for msg in page.messages {
+                if chat_history.current().iter().any(|seen| seen.id == msg.id) {
+                    continue;
+                }
+                if let ChatMessage::TextMessage { username, .. } = &msg.message {
+                    request_pubkey(&pubkeys, &origin, username);
+                }
+                chat_history.insert(index, msg);
+                index += 1;
+            }
+            Ok::<(), String>(())
+        })
+    };
+    let load_older_cb = {
+        let load_older = load_older.clone();
+        Callback::from(move |_| load_older.run())
+    };
+
+    // The message whose thread is open, if any. New messages are sent as replies to it.
+    let open_thread = use_state_eq(|| None::<MessageId>);
+    let load_thread = {
+        let chat_history = chat_history.clone();
+        let pubkeys = pubkeys.clone();
+        let origin = loc.origin.clone();
+        let open_thread = open_thread.clone();
+        use_async(async move {
+            let Some(id) = *open_thread else {
+                return Ok(());
+            };
+            let thread: Thread = reqwest::get(format!("{origin}/messages/{id}/thread"))
+                .await
+                .map_err(|why| format!("Error fetching thread: {why}"))?
+                .json()
+                .await
+                .map_err(|why| format!("Error reading thread: {why}"))?;
+            // Our copy of the parent's reply count may be out of date.
+            let index = chat_history.current().iter().position(|seen| seen.id == thread.parent.id);
+            if let Some(index) = index {
+                chat_history.update(index, thread.parent);
+            }
+            for msg in thread.replies {
+                if chat_history.current().iter().any(|seen| seen.id == msg.id) {
+                    continue;
+                }
+                if let ChatMessage::TextMessage { username, .. } = &msg.message {
+                    request_pubkey(&pubkeys, &origin, username);
+                }
+                chat_history.push(msg);
+            }
+            Ok::<(), String>(())
+        })
+    };
+    {
+        let load_thread = load_thread.clone();
+        use_effect_with_deps(
+            move |open_thread| {
+                if open_thread.is_some() {
+                    load_thread.run();
+                }
+            },
+            *open_thread,
+        );
+    }
+    let open_thread_cb = {
+        let open_thread = open_thread.clone();
+        Callback::from(move |id: MessageId| open_thread.set(Some(id)))
+    };
+    let close_thread_cb = {
+        let open_thread = open_thread.clone();
+        Callback::from(move |_| open_thread.set(None))
+    };
+
+    // Clicking a push notification opens the app at its link.
+    // The message to scroll to once it has been loaded:
+    let scroll_to = use_state_eq(|| None::<MessageId>);
+    {
+        let stored_room = stored_room.clone();
+        let open_thread = open_thread.clone();
+        let scroll_to = scroll_to.clone();
+        let pathname = loc.pathname.clone();
+        use_effect_with_deps(
+            move |search: &String| {
+                if search.is_empty() {
+                    return;
+                }
+                if let Some(link) = NotificationLink::parse(search) {
+                    // The connection isn't open yet, and joins the stored room once it is.
+                    if let Some(room) = link.room {
+                        stored_room.set(room);
+                    }
+                    open_thread.set(link.thread);
+                    scroll_to.set(link.message);
+                }
+                // So that reloading the page doesn't jump back to the message
+                let history = web_sys::window().unwrap_throw().history().unwrap_throw();
+                let _ = history.replace_state_with_url(&JsValue::NULL, "", Some(&pathname));
+            },
+            loc.search.clone(),
+        );
+    }
+    {
+        // Try again whenever more messages are loaded, until the message is there
+        // or there is nothing left to load.
+        let deps = (
+            *scroll_to,
+            chat_history.current().len(),
+            load_older.loading,
+            load_thread.loading,
+            *has_older,
+        );
+        let scroll_to = scroll_to.clone();
+        let chat_history = chat_history.clone();
+        let load_older = load_older.clone();
+        let is_direct = direct_peer(&current_room).is_some();
+        use_effect_with_deps(
+            move |(scroll_to_id, _, loading_older, loading_thread, has_older)| {
+                let Some(id) = *scroll_to_id else {
+                    return;
+                };
+                let element = web_sys::window()
+                    .and_then(|window| window.document())
+                    .and_then(|document| document.get_element_by_id(&format!("message-{id}")));
+                if let Some(element) = element {
+                    element.scroll_into_view();
+                    scroll_to.set(None);
+                    return;
+                }
+                if *loading_older || *loading_thread {
+                    return;
+                }
+                let loaded = chat_history.current().iter().any(|msg| msg.id == Some(id));
+                if !loaded && *has_older && !is_direct {
+                    load_older.run();
+                } else {
+                    // It was deleted, or isn't shown anywhere, so stop waiting for it.
+                    scroll_to.set(None);
+                }
+            },
+            deps,
+        );
+    }
+
     let text_value = use_state(|| String::new());
     let oninput_cb = {
         let text_value = text_value.clone();
@@ -57,11 +423,207 @@ pub fn ChatWindow() -> Html {
         })
     };
 
+    // Encrypting a direct message needs the recipient's key, so fetch it as soon as the conversation is opened.
+    {
+        let pubkeys = pubkeys.clone();
+        let origin = loc.origin.clone();
+        use_effect_with_deps(
+            move |current_room| {
+                if let Some(peer) = direct_peer(current_room) {
+                    request_pubkey(&pubkeys, &origin, peer);
+                }
+            },
+            current_room.clone(),
+        );
+    }
+    let encrypt_dms = use_state(|| true);
+    let encrypt_dms_cb = {
+        let encrypt_dms = encrypt_dms.clone();
+        Callback::from(move |_| encrypt_dms.set(!*encrypt_dms))
+    };
+
+    let edit_cb = {
+        let ws_conn = ws_conn.clone();
+        let privkey = privkey.clone();
+        let pubkeys = pubkeys.clone();
+        let me = me.clone();
+        Callback::from(move |(message, new_text): (ServerMessage, String)| {
+            let Some(id) = message.id else {
+                return;
+            };
+            let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+                .expect_throw("invalid stored jwk key");
+            // An encrypted message stays encrypted after it's edited.
+            let new_content = match &message.message {
+                ChatMessage::DirectMessage { from, to, encrypted: true, .. } => {
+                    match pubkeys.current().get(conversation_peer(from, to, &me)) {
+                        Some(KeyLookup::Found(peer_key)) => encryption::encrypt(&privkey, peer_key, from, to, &new_text),
+                        _ => return,
+                    }
+                }
+                _ => new_text,
+            };
+            let signature = common::signing::sign(
+                &privkey,
+                &common::signing::edit_message_payload(id, &new_content),
+            );
+            ws_conn.send(
+                serde_json::to_string(&ChatMessage::EditMessage {
+                    id,
+                    new_content,
+                    signature: Some(signature),
+                })
+                .unwrap(),
+            );
+        })
+    };
+
+    let delete_cb = {
+        let ws_conn = ws_conn.clone();
+        Callback::from(move |id: MessageId| {
+            // The connection is authenticated as us, so the server knows that we're the author.
+            ws_conn.send(serde_json::to_string(&ChatMessage::DeleteMessage { id }).unwrap());
+        })
+    };
+
+    let react_cb = {
+        let ws_conn = ws_conn.clone();
+        Callback::from(move |(message_id, emoji, add): (MessageId, String, bool)| {
+            ws_conn.send(
+                serde_json::to_string(&ChatMessage::Reaction {
+                    message_id,
+                    emoji,
+                    add,
+                    // The server fills this in
+                    username: String::new(),
+                })
+                .unwrap(),
+            );
+        })
+    };
+
+    let select_room_cb = {
+        let ws_conn = ws_conn.clone();
+        let stored_room = stored_room.clone();
+        let has_older = has_older.clone();
+        let open_thread = open_thread.clone();
+        Callback::from(move |room: String| {
+            // The server ignores this if we are already in the room.
+            if direct_peer(&room).is_none() {
+                ws_conn.send(serde_json::to_string(&ChatMessage::JoinRoom { room: room.clone(), since: None }).unwrap());
+            }
+            has_older.set(true);
+            open_thread.set(None);
+            stored_room.set(room);
+        })
+    };
+    // When the app is already open, the service worker sends it the links of the notifications that are clicked.
+    {
+        let select_room_cb = select_room_cb.clone();
+        let open_thread = open_thread.clone();
+        let scroll_to = scroll_to.clone();
+        use_effect_with_deps(
+            move |_| {
+                let handler = Closure::<dyn Fn(String)>::new(move |search: String| {
+                    let Some(link) = NotificationLink::parse(&search) else {
+                        return;
+                    };
+                    if let Some(room) = link.room {
+                        select_room_cb.emit(room);
+                    }
+                    open_thread.set(link.thread);
+                    scroll_to.set(link.message);
+                });
+                #[allow(unused_unsafe)]  // this unsafe is actually needed
+                unsafe { set_open_link_handler(handler.as_ref().clone()); }
+                move || {
+                    #[allow(unused_unsafe)]
+                    unsafe { set_open_link_handler(JsValue::NULL); }
+                    drop(handler);
+                }
+            },
+            (),
+        );
+    }
+
+    let leave_room_cb = {
+        let ws_conn = ws_conn.clone();
+        let stored_room = stored_room.clone();
+        let current_room = current_room.clone();
+        Callback::from(move |_| {
+            if direct_peer(&current_room).is_none() {
+                ws_conn.send(
+                    serde_json::to_string(&ChatMessage::LeaveRoom {
+                        room: current_room.clone(),
+                    })
+                    .unwrap(),
+                );
+            }
+            stored_room.set(DEFAULT_ROOM.to_string());
+        })
+    };
+
     let send_cb = {
         let ws_conn = ws_conn.clone();
         let text_value = text_value.clone();
+        let username = username.clone();
+        let privkey = privkey.clone();
+        let current_room = current_room.clone();
+        let pubkeys = pubkeys.clone();
+        let encrypt_dms = encrypt_dms.clone();
+        let open_thread = open_thread.clone();
         Callback::from(move |e: SubmitEvent| {
-            ws_conn.send((*text_value).clone());
+            let username = (*username)
+                .clone()
+                .expect_throw("no username while in chat window code?!");
+            let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+                .expect_throw("invalid stored jwk key");
+            let content = (*text_value).clone();
+            let message = match direct_peer(&current_room) {
+                Some(peer) => {
+                    let to = peer.to_string();
+                    let peer_key = match pubkeys.current().get(peer) {
+                        Some(KeyLookup::Found(key)) => Some(*key),
+                        _ => None,
+                    };
+                    let (content, encrypted) = match (*encrypt_dms, peer_key) {
+                        (true, Some(peer_key)) => (
+                            encryption::encrypt(&privkey, &peer_key, &username, &to, &content),
+                            true,
+                        ),
+                        // The send button is disabled until the key is loaded.
+                        (true, None) => return e.prevent_default(),
+                        (false, _) => (content, false),
+                    };
+                    let signature = common::signing::sign(
+                        &privkey,
+                        &common::signing::direct_message_payload(&username, &to, &content),
+                    );
+                    ChatMessage::DirectMessage {
+                        from: username,
+                        to,
+                        content,
+                        signature: Some(signature),
+                        encrypted,
+                    }
+                }
+                None => {
+                    let room = current_room.clone();
+                    let reply_to = *open_thread;
+                    let signature = common::signing::sign(
+                        &privkey,
+                        &common::signing::text_message_payload(&room, &username, &content, reply_to),
+                    );
+                    ChatMessage::TextMessage {
+                        room,
+                        username,
+                        content,
+                        signature: Some(signature),
+                        reply_to,
+                    }
+                }
+            };
+            ws_conn.send(serde_json::to_string(&message).unwrap());
             text_value.set(String::new());
             e.prevent_default();
         })
@@ -70,6 +632,7 @@ pub fn ChatWindow() -> Html {
     match *ws_conn.ready_state {
         UseWebSocketReadyState::Connecting => {
             did_send_username.set(false);
+            auth_challenge.set(None);
             html!(<h2>{"Connecting to chat websocket..."}</h2>)
         }
         UseWebSocketReadyState::Closing => {
@@ -77,32 +640,214 @@ pub fn ChatWindow() -> Html {
         }
         UseWebSocketReadyState::Closed => {
             did_send_username.set(false);
+            auth_challenge.set(None);
             html!(<h2>{"Websocket is closed, reconnecting..."}</h2>)
         }
         UseWebSocketReadyState::Open => {
-            if !(*did_send_username) {
+            // Once the server sends its challenge, sign it to prove that we own this username.
+            if let (false, Some(nonce)) = (*did_send_username, &*auth_challenge) {
                 let username = username.clone();
                 let username = (*username)
                     .clone()
                     .expect_throw("no username while in chat window code?!");
+                let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+                    .expect_throw("invalid stored jwk key");
+                let signature = common::signing::sign(
+                    &privkey,
+                    &common::signing::challenge_payload(&username, nonce),
+                );
+                // If this is a reconnect, ask the server for everything we missed while we were gone.
+                // Messages that we made up ourselves have sequence number 0, so they don't count.
+                let last_seen = chat_history
+                    .current()
+                    .iter()
+                    .map(|msg| msg.seq)
+                    .max()
+                    .filter(|seq| *seq != 0);
                 ws_conn.send(
-                    serde_json::to_string(&ChatMessage::ConnectionUsername { username }).unwrap(),
+                    serde_json::to_string(&ChatMessage::ConnectionUsername {
+                        username,
+                        signature: Some(signature),
+                        last_seen,
+                    })
+                    .unwrap(),
                 );
+                // We're already in the default room, so joining it again only replays what we missed in it.
+                if last_seen.is_some() {
+                    ws_conn.send(
+                        serde_json::to_string(&ChatMessage::JoinRoom {
+                            room: DEFAULT_ROOM.to_string(),
+                            since: last_seen,
+                        })
+                        .unwrap(),
+                    );
+                }
+                // The server puts every new connection in the default room, so we need to rejoin the one we're looking at.
+                if current_room != DEFAULT_ROOM && direct_peer(&current_room).is_none() {
+                    ws_conn.send(
+                        serde_json::to_string(&ChatMessage::JoinRoom {
+                            room: current_room.clone(),
+                            since: last_seen,
+                        })
+                        .unwrap(),
+                    );
+                }
                 did_send_username.set(true);
             }
+            // Everyone we've exchanged direct messages with, in the order the conversations started
+            let mut conversations = Vec::new();
+            for message in chat_history.current().iter() {
+                if let ChatMessage::DirectMessage { from, to, .. } = &message.message {
+                    let peer = conversation_peer(from, to, &me).to_string();
+                    if !conversations.contains(&peer) {
+                        conversations.push(peer);
+                    }
+                }
+            }
+            let is_direct = direct_peer(&current_room).is_some();
+            let peer_key = direct_peer(&current_room).and_then(|peer| pubkeys.current().get(peer).cloned());
+            let waiting_for_key = *encrypt_dms && !matches!(peer_key, Some(KeyLookup::Found(_)));
+            let my_key = privkey.as_ref().and_then(|jwk| SecretKey::from_jwk_str(jwk).ok());
+            let render_message = |message: &ServerMessage, in_thread: bool| {
+                let key = match &message.message {
+                    ChatMessage::TextMessage { username, .. } => pubkeys.current().get(username).cloned(),
+                    ChatMessage::DirectMessage { from, .. } => pubkeys.current().get(from).cloned(),
+                    _ => None,
+                };
+                let decrypted = match &message.message {
+                    ChatMessage::DirectMessage { from, to, content, encrypted: true, .. } => {
+                        let peer = conversation_peer(from, to, &me);
+                        match (&my_key, pubkeys.current().get(peer)) {
+                            (Some(my_key), Some(KeyLookup::Found(peer_key))) => {
+                                encryption::decrypt(my_key, peer_key, from, to, content)
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                // Only our own stored messages can be edited or deleted,
+                // and encrypted ones can only be edited once we can decrypt them.
+                let is_own = match &message.message {
+                    ChatMessage::TextMessage { username, .. } => *username == me,
+                    ChatMessage::DirectMessage { from, .. } => *from == me,
+                    _ => false,
+                };
+                let can_decrypt = !matches!(message.message, ChatMessage::DirectMessage { encrypted: true, .. }) || decrypted.is_some();
+                let (on_edit, on_delete) = match message.id {
+                    Some(id) if is_own => {
+                        let message = message.clone();
+                        let on_edit = can_decrypt.then(|| edit_cb.reform(move |new_text| (message.clone(), new_text)));
+                        (on_edit, Some(delete_cb.reform(move |_| id)))
+                    }
+                    _ => (None, None),
+                };
+                let on_react = message.id.map(|id| react_cb.reform(move |(emoji, add)| (id, emoji, add)));
+                // Threads can only be started from room messages, and replies can't have threads of their own.
+                let on_open_thread = match (&message.message, message.id) {
+                    (ChatMessage::TextMessage { reply_to: None, .. }, Some(id)) if !in_thread => {
+                        Some(open_thread_cb.reform(move |_| id))
+                    }
+                    _ => None,
+                };
+                html! {
+                    <MessageDisplay message={message.clone()} sender_key={key} {decrypted} {on_edit} {on_delete} me={me.clone()} {on_react} {on_open_thread} />
+                }
+            };
             html!(
                 <div>
+                    <RoomSwitcher current={current_room.clone()} on_select={select_room_cb} {conversations} />
                     {
-                        for chat_history.current().iter().map(|message| {
-                            html! {
-                                <MessageDisplay message={message.clone()} />
+                        match direct_peer(&current_room) {
+                            Some(peer) => html! { <h2>{format!("Direct messages with {peer}")}</h2> },
+                            None => html! { <h2>{format!("#{current_room}")}</h2> },
+                        }
+                    }
+                    {
+                        if current_room != DEFAULT_ROOM {
+                            html! { <button onclick={leave_room_cb}>{if is_direct { "Close conversation" } else { "Leave room" }}</button> }
+                        } else {
+                            html! {}
+                        }
+                    }
+                    {
+                        // Older direct messages can't be loaded yet, only the recent ones that the server sends when we connect.
+                        if *has_older && !is_direct {
+                            html! { <button onclick={load_older_cb} disabled={load_older.loading}>{"Load older messages"}</button> }
+                        } else {
+                            html! {}
+                        }
+                    }
+                    {
+                        if let Some(error) = &load_older.error {
+                            html! { <p style="text-color: red;">{error}</p> }
+                        } else {
+                            html! {}
+                        }
+                    }
+                    {
+                        for chat_history.current().iter().filter(|message| is_in_view(&message.message, &current_room, &me)).map(|message| render_message(message, false))
+                    }
+                    {
+                        match *open_thread {
+                            Some(id) => {
+                                let parent = chat_history.current().iter().find(|message| message.id == Some(id)).cloned();
+                                let mut replies = chat_history
+                                    .current()
+                                    .iter()
+                                    .filter(|message| matches!(message.message, ChatMessage::TextMessage { reply_to: Some(parent), .. } if parent == id))
+                                    .cloned()
+                                    .collect::<Vec<_>>();
+                                replies.sort_by_key(|message| message.seq);
+                                html! {
+                                    <div>
+                                        <h3>{"Thread"}</h3>
+                                        <button onclick={close_thread_cb}>{"Close thread"}</button>
+                                        { for parent.iter().map(|message| render_message(message, true)) }
+                                        <div style="margin-left: 2em;">
+                                            { for replies.iter().map(|message| render_message(message, true)) }
+                                        </div>
+                                        {
+                                            if let Some(error) = &load_thread.error {
+                                                html! { <p style="text-color: red;">{error}</p> }
+                                            } else {
+                                                html! {}
+                                            }
+                                        }
+                                    </div>
+                                }
                             }
-                        })
+                            None => html! {},
+                        }
                     }
                     <form onsubmit={send_cb}>
-                        <input type="text" oninput={oninput_cb} value={(*text_value).clone()} />
-                        <input type="submit" value="Send!" />
+                        <input type="text" oninput={oninput_cb} value={(*text_value).clone()} placeholder={if open_thread.is_some() { "Reply in thread" } else { "" }} />
+                        <input type="submit" value="Send!" disabled={is_direct && waiting_for_key} />
+                        {
+                            if is_direct {
+                                html! {
+                                    <label>
+                                        <input type="checkbox" checked={*encrypt_dms} onclick={encrypt_dms_cb} />
+                                        {"End-to-end encrypted"}
+                                    </label>
+                                }
+                            } else {
+                                html! {}
+                            }
+                        }
                     </form>
+                    {
+                        match (is_direct && *encrypt_dms, &peer_key) {
+                            (true, Some(KeyLookup::NotRegistered)) => html! { <p>{"This user is not registered, so messages to them can't be encrypted."}</p> },
+                            (true, Some(KeyLookup::Failed(why))) => html! { <p style="text-color: red;">{why}</p> },
+                            (true, Some(KeyLookup::Pending) | None) => html! { <p>{"Loading their key..."}</p> },
+                            _ => html! {},
+                        }
+                    }
+                    <div>
+                        <p>{"Search the chat history"}</p>
+                        <SearchBox />
+                    </div>
                     <div>
                         <p>{"Push notification config (if you can't see this, reload the page)"}</p>
                         <WebPushSetup />
@@ -115,24 +860,326 @@ pub fn ChatWindow() -> Html {
 
 #[derive(Properties, PartialEq, Clone)]
 struct MessageDisplayProps {
-    pub message: ChatMessage,
+    pub message: ServerMessage,
+    /// The key of the user that the message claims to be from, if we've looked it up.
+    pub sender_key: Option<KeyLookup>,
+    /// For encrypted direct messages, the plaintext, if we could decrypt it.
+    #[prop_or_default]
+    pub decrypted: Option<String>,
+    /// Called with the new text when the user edits the message. `None` if they can't.
+    #[prop_or_default]
+    pub on_edit: Option<Callback<String>>,
+    /// Called when the user deletes the message. `None` if they can't.
+    #[prop_or_default]
+    pub on_delete: Option<Callback<()>>,
+    /// Our username, to show which reactions and mentions are ours.
+    pub me: String,
+    /// Called with an emoji, and whether to add or remove it, when the user reacts to the message.
+    /// `None` if the message can't be reacted to.
+    #[prop_or_default]
+    pub on_react: Option<Callback<(String, bool)>>,
+    /// Called when the user opens the message's thread. `None` if the message can't have one.
+    #[prop_or_default]
+    pub on_open_thread: Option<Callback<()>>,
+}
+
+/// After a message is edited, its signature is over the edit instead of the original message.
+fn signed_payload(message: &ServerMessage, content: &str, original: Vec<u8>) -> Vec<u8> {
+    match (message.id, message.edited_at) {
+        (Some(id), Some(_)) => common::signing::edit_message_payload(id, content),
+        _ => original,
+    }
+}
+
+/// Show the text of a message, with mentions of us highlighted.
+fn highlight_mentions(content: &str, me: &str) -> Html {
+    let mut parts = Vec::new();
+    let mut shown_until = 0;
+    for mention in common::mentions::find_mentions(content) {
+        if &content[mention.start + 1..mention.end] != me {
+            continue;
+        }
+        parts.push(html! { {&content[shown_until..mention.start]} });
+        parts.push(html! { <mark>{&content[mention.clone()]}</mark> });
+        shown_until = mention.end;
+    }
+    parts.push(html! { {&content[shown_until..]} });
+    html! { for parts }
 }
 
 #[function_component]
 fn MessageDisplay(props: &MessageDisplayProps) -> Html {
-    match &props.message {
+    let timestamp = props.message.timestamp;
+    // Lets notifications link to the message
+    let anchor = props.message.id.map(|id| format!("message-{id}"));
+    let edited = match (props.message.id, props.message.edited_at) {
+        (Some(id), Some(edited_at)) => {
+            // Revisions of direct messages are private, so the server won't give them to us.
+            let public = matches!(props.message.message, ChatMessage::TextMessage { .. });
+            html! { <EditedMarker {id} {edited_at} {public} /> }
+        }
+        _ => html! {},
+    };
+    let edit_button = |current_text: &str| match &props.on_edit {
+        Some(on_edit) => {
+            let on_edit = on_edit.clone();
+            let current_text = current_text.to_string();
+            let onclick = Callback::from(move |_| {
+                let new_text = web_sys::window()
+                    .unwrap_throw()
+                    .prompt_with_message_and_default("Edit message", &current_text);
+                if let Ok(Some(new_text)) = new_text {
+                    if new_text != current_text {
+                        on_edit.emit(new_text);
+                    }
+                }
+            });
+            html! { <button {onclick}>{"Edit"}</button> }
+        }
+        None => html! {},
+    };
+    let delete_button = match &props.on_delete {
+        Some(on_delete) => {
+            let on_delete = on_delete.clone();
+            let onclick = Callback::from(move |_| {
+                let confirmed = web_sys::window()
+                    .unwrap_throw()
+                    .confirm_with_message("Delete this message for everyone?");
+                if confirmed == Ok(true) {
+                    on_delete.emit(());
+                }
+            });
+            html! { <button {onclick}>{"Delete"}</button> }
+        }
+        None => html! {},
+    };
+    let reactions = match &props.on_react {
+        Some(on_react) => html! {
+            <ReactionChips reactions={props.message.reactions.clone()} me={props.me.clone()} on_react={on_react.clone()} />
+        },
+        None => html! {},
+    };
+    let thread_button = match &props.on_open_thread {
+        Some(on_open_thread) => {
+            let label = match props.message.reply_count {
+                0 => "Reply".to_string(),
+                1 => "1 reply".to_string(),
+                count => format!("{count} replies"),
+            };
+            html! { <button onclick={on_open_thread.reform(|_| ())}>{label}</button> }
+        }
+        None => html! {},
+    };
+    match &props.message.message {
         ChatMessage::TextMessage {
+            room,
             username,
             content,
             signature,
-        } => html! {
-            <p><span style="text-color: blue;">{&username}</span>{":"}<span>{&content}</span></p>
-        },
-        ChatMessage::SystemMessage { content } => html! {
-            <p style="text-color: red;">{&content}</p>
+            reply_to,
+        } => {
+            let verification = Verification::check(
+                props.sender_key.as_ref(),
+                &signed_payload(
+                    &props.message,
+                    content,
+                    common::signing::text_message_payload(room, username, content, *reply_to),
+                ),
+                signature.as_deref(),
+            );
+            html! {
+                <p id={anchor}><Timestamp millis={timestamp} /><SignatureBadge {verification} /><span style="text-color: blue;">{&username}</span>{":"}<span>{highlight_mentions(content, &props.me)}</span>{edited}{edit_button(content)}{delete_button}{reactions}{thread_button}</p>
+            }
+        }
+        ChatMessage::DirectMessage {
+            from,
+            to,
+            content,
+            signature,
+            encrypted,
+        } => {
+            let verification = Verification::check(
+                props.sender_key.as_ref(),
+                &signed_payload(
+                    &props.message,
+                    content,
+                    common::signing::direct_message_payload(from, to, content),
+                ),
+                signature.as_deref(),
+            );
+            let text = match (encrypted, &props.decrypted) {
+                (false, _) => html! { <span>{&content}</span> },
+                (true, Some(plaintext)) => html! { <span title="End-to-end encrypted">{"\u{1F512} "}{plaintext}</span> },
+                (true, None) => html! { <span style="font-style: italic;">{"\u{1F512} Encrypted message (can't decrypt it yet)"}</span> },
+            };
+            let current_text = props.decrypted.as_deref().unwrap_or(content);
+            html! {
+                <p id={anchor}><Timestamp millis={timestamp} /><SignatureBadge {verification} /><span style="text-color: blue;">{&from}</span>{":"}{text}{edited}{edit_button(current_text)}{delete_button}{reactions}</p>
+            }
+        }
+        ChatMessage::SystemMessage { content, .. } => html! {
+            <p style="text-color: red;"><Timestamp millis={timestamp} />{&content}</p>
         },
-        ChatMessage::ConnectionUsername { .. } => {
+        ChatMessage::AuthChallenge { .. }
+        | ChatMessage::ConnectionUsername { .. }
+        | ChatMessage::JoinRoom { .. }
+        | ChatMessage::LeaveRoom { .. }
+        | ChatMessage::EditMessage { .. }
+        | ChatMessage::DeleteMessage { .. }
+        | ChatMessage::Reaction { .. } => {
             html! {<h1>{format!("{:?} (should never see this)", &props.message)}</h1>}
         }
     }
 }
+
+#[derive(Properties, PartialEq, Clone)]
+struct ReactionChipsProps {
+    pub reactions: Vec<ReactionSummary>,
+    pub me: String,
+    pub on_react: Callback<(String, bool)>,
+}
+
+/// Shows how many people reacted with each emoji. Clicking one adds or takes back our reaction.
+#[function_component]
+fn ReactionChips(props: &ReactionChipsProps) -> Html {
+    let add_reaction = {
+        let on_react = props.on_react.clone();
+        Callback::from(move |_| {
+            let emoji = web_sys::window()
+                .unwrap_throw()
+                .prompt_with_message_and_default("React with", "\u{1F44D}");
+            if let Ok(Some(emoji)) = emoji {
+                let emoji = emoji.trim().to_string();
+                if !emoji.is_empty() {
+                    on_react.emit((emoji, true));
+                }
+            }
+        })
+    };
+    html! {
+        <span>
+            {
+                for props.reactions.iter().map(|summary| {
+                    let mine = summary.users.contains(&props.me);
+                    let onclick = {
+                        let on_react = props.on_react.clone();
+                        let emoji = summary.emoji.clone();
+                        Callback::from(move |_| on_react.emit((emoji.clone(), !mine)))
+                    };
+                    let style = if mine { "font-weight: bold;" } else { "" };
+                    html! {
+                        <button {onclick} {style} title={summary.users.join(", ")}>
+                            {format!("{} {}", summary.emoji, summary.users.len())}
+                        </button>
+                    }
+                })
+            }
+            <button onclick={add_reaction} title="Add a reaction">{"+"}</button>
+        </span>
+    }
+}
+
+#[derive(Properties, PartialEq, Clone)]
+struct EditedMarkerProps {
+    pub id: MessageId,
+    pub edited_at: i64,
+    /// Whether the earlier versions of the message can be fetched from the server.
+    pub public: bool,
+}
+
+/// Marks a message as edited, and shows its earlier versions when clicked.
+#[function_component]
+fn EditedMarker(props: &EditedMarkerProps) -> Html {
+    let loc = use_location();
+    let shown = use_state_eq(|| false);
+    let revisions = {
+        let id = props.id;
+        use_async(async move {
+            reqwest::get(format!("{}/messages/{id}/revisions", loc.origin))
+                .await
+                .map_err(|why| format!("Error fetching earlier versions: {why}"))?
+                .json::<Vec<MessageRevision>>()
+                .await
+                .map_err(|why| format!("Error reading earlier versions: {why}"))
+        })
+    };
+    let title = format!("Edited {}", format_timestamp(props.edited_at));
+    if !props.public {
+        return html! { <span style="color: gray;" {title}>{" (edited)"}</span> };
+    }
+
+    let onclick = {
+        let shown = shown.clone();
+        let revisions = revisions.clone();
+        Callback::from(move |_| {
+            if !*shown && revisions.data.is_none() {
+                revisions.run();
+            }
+            shown.set(!*shown);
+        })
+    };
+    html! {
+        <>
+            <button {onclick} {title}>{if *shown { "(edited, hide earlier versions)" } else { "(edited)" }}</button>
+            {
+                if *shown {
+                    match (&revisions.data, &revisions.error) {
+                        (_, Some(error)) => html! { <span style="text-color: red;">{error}</span> },
+                        (Some(revisions), _) => html! {
+                            <ul>
+                                {
+                                    for revisions.iter().map(|revision| html! {
+                                        <li><Timestamp millis={revision.timestamp} />{&revision.content}</li>
+                                    })
+                                }
+                            </ul>
+                        },
+                        (None, None) => html! { <span>{"Loading earlier versions..."}</span> },
+                    }
+                } else {
+                    html! {}
+                }
+            }
+        </>
+    }
+}
+
+/// Show a time in the browser's locale and time zone.
+fn format_timestamp(millis: i64) -> String {
+    js_sys::Date::new(&JsValue::from_f64(millis as f64))
+        .to_locale_string("default", &JsValue::UNDEFINED)
+        .into()
+}
+
+#[derive(Properties, PartialEq, Clone)]
+pub struct TimestampProps {
+    /// Milliseconds since the Unix epoch.
+    pub millis: i64,
+}
+
+#[function_component]
+pub fn Timestamp(props: &TimestampProps) -> Html {
+    html! {
+        <span style="color: gray; font-size: small;">{format_timestamp(props.millis)}{" "}</span>
+    }
+}
+
+#[derive(Properties, PartialEq, Clone)]
+struct SignatureBadgeProps {
+    pub verification: Verification,
+}
+
+#[function_component]
+fn SignatureBadge(props: &SignatureBadgeProps) -> Html {
+    match &props.verification {
+        Verification::Verified => html! {
+            <span style="color: green;" title="Signed by this user's registered key">{"[verified] "}</span>
+        },
+        Verification::Unverified(why) => html! {
+            <span style="color: gray;" title={why.clone()}>{"[unverified] "}</span>
+        },
+        Verification::Invalid => html! {
+            <span style="color: red;" title="The signature does not match this user's registered key">{"[INVALID SIGNATURE] "}</span>
+        },
+    }
+}
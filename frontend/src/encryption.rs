@@ -0,0 +1,77 @@
+//! End-to-end encryption for direct messages.
+//!
+//! Both users already have k256 keys, so the sender and the recipient can each derive
+//! the same secret with ECDH (our secret key and their public key), without the server ever seeing it.
+//! That secret goes through HKDF-SHA256 to get an AES-256-GCM key.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::Engine;
+use hkdf::Hkdf;
+use k256::{ecdh::diffie_hellman, PublicKey, SecretKey};
+use sha2::Sha256;
+
+/// Length of the AES-GCM nonce that is put in front of the ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Derive the cipher that two users share.
+/// It is the same no matter which of the two users calls this.
+fn shared_cipher(my_key: &SecretKey, their_key: &PublicKey) -> Aes256Gcm {
+    let shared_secret = diffie_hellman(my_key.to_nonzero_scalar(), their_key.as_affine());
+    let hkdf = Hkdf::<Sha256>::new(None, shared_secret.raw_secret_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(b"simple-websocket-chat direct message", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Aes256Gcm::new(&key.into())
+}
+
+/// The sender and recipient are authenticated along with the ciphertext,
+/// so that the server can't pass off a message as being between different users.
+fn associated_data(from: &str, to: &str) -> Vec<u8> {
+    format!("{}\n{from}\n{to}", from.len()).into_bytes()
+}
+
+/// Encrypt a direct message from `from` to `to`.
+/// `their_key` is the key of the other user in the conversation.
+///
+/// Returns the base64 nonce followed by the ciphertext, which goes in `ChatMessage::DirectMessage::content`.
+pub fn encrypt(my_key: &SecretKey, their_key: &PublicKey, from: &str, to: &str, plaintext: &str) -> String {
+    let cipher = shared_cipher(my_key, their_key);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: &associated_data(from, to),
+            },
+        )
+        .expect("encrypting into a Vec should never fail");
+    let mut content = nonce.to_vec();
+    content.extend(ciphertext);
+    base64::engine::general_purpose::STANDARD.encode(content)
+}
+
+/// Decrypt the content of a direct message from `from` to `to`.
+/// `their_key` is the key of the other user in the conversation, whichever side they are on.
+///
+/// Returns `None` if the message was tampered with or wasn't encrypted for us.
+pub fn decrypt(my_key: &SecretKey, their_key: &PublicKey, from: &str, to: &str, content: &str) -> Option<String> {
+    let content = base64::engine::general_purpose::STANDARD.decode(content).ok()?;
+    if content.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+    let plaintext = shared_cipher(my_key, their_key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &associated_data(from, to),
+            },
+        )
+        .ok()?;
+    String::from_utf8(plaintext).ok()
+}
@@ -7,6 +7,10 @@ use yew::prelude::*;
 use yew_hooks::prelude::*;
 
 mod chat_window;
+mod encryption;
+mod pubkeys;
+mod rooms;
+mod search;
 mod web_push;
 
 #[function_component]
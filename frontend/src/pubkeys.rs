@@ -0,0 +1,95 @@
+use k256::PublicKey;
+use reqwest::StatusCode;
+use yew_hooks::prelude::*;
+
+/// The state of fetching a user's public key from the server.
+#[derive(Clone, PartialEq, Debug)]
+pub enum KeyLookup {
+    /// The request for this key is still in flight.
+    Pending,
+    /// The server says nobody has registered this username.
+    NotRegistered,
+    Found(PublicKey),
+    /// The key could not be fetched or parsed; the string says why.
+    Failed(String),
+}
+
+/// Fetch a user's key from `/pubkey/:username`.
+pub async fn fetch_pubkey(origin: &str, username: &str) -> KeyLookup {
+    let client = reqwest::Client::builder()
+        .build()
+        .expect("Failed to build client");
+    // Usernames may contain characters that mean something in a URL
+    let username = js_sys::encode_uri_component(username);
+    let result = client
+        .get(format!("{origin}/pubkey/{username}"))
+        .send()
+        .await;
+    match result {
+        Err(why) => KeyLookup::Failed(format!("Error fetching key: {why}")),
+        Ok(res) => {
+            if res.status() == StatusCode::NOT_FOUND {
+                return KeyLookup::NotRegistered;
+            }
+            if res.status() != StatusCode::OK {
+                return KeyLookup::Failed(format!("Server returned {}", res.status()));
+            }
+            match res.text().await {
+                Err(why) => KeyLookup::Failed(format!("Error reading key: {why}")),
+                Ok(jwk) => match PublicKey::from_jwk_str(&jwk) {
+                    Ok(key) => KeyLookup::Found(key),
+                    Err(why) => KeyLookup::Failed(format!("Server sent an invalid key: {why}")),
+                },
+            }
+        }
+    }
+}
+
+/// Start fetching a user's key in the background, unless it is already in the cache.
+pub fn request_pubkey(pubkeys: &UseMapHandle<String, KeyLookup>, origin: &str, username: &str) {
+    if pubkeys.current().contains_key(username) {
+        return;
+    }
+    pubkeys.insert(username.to_string(), KeyLookup::Pending);
+    let pubkeys = pubkeys.clone();
+    let origin = origin.to_string();
+    let username = username.to_string();
+    yew::platform::spawn_local(async move {
+        let lookup = fetch_pubkey(&origin, &username).await;
+        pubkeys.insert(username, lookup);
+    });
+}
+
+/// Whether a message was signed by the user it claims to be from.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Verification {
+    Verified,
+    /// The message can't be checked (no signature, unregistered user, or the key is still loading).
+    Unverified(String),
+    /// The message claims to be signed, but the signature does not match the user's key.
+    Invalid,
+}
+
+impl Verification {
+    pub fn check(key: Option<&KeyLookup>, payload: &[u8], signature: Option<&str>) -> Self {
+        let Some(signature) = signature else {
+            return Verification::Unverified("message is not signed".to_string());
+        };
+        match key {
+            None | Some(KeyLookup::Pending) => {
+                Verification::Unverified("loading sender's key...".to_string())
+            }
+            Some(KeyLookup::NotRegistered) => {
+                Verification::Unverified("sender is not a registered user".to_string())
+            }
+            Some(KeyLookup::Failed(why)) => Verification::Unverified(why.clone()),
+            Some(KeyLookup::Found(key)) => {
+                if common::signing::verify(key, payload, signature) {
+                    Verification::Verified
+                } else {
+                    Verification::Invalid
+                }
+            }
+        }
+    }
+}
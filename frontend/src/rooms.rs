@@ -0,0 +1,153 @@
+use reqwest::StatusCode;
+use wasm_bindgen::{JsCast, UnwrapThrowExt};
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_hooks::prelude::*;
+
+#[derive(Properties, PartialEq, Clone)]
+pub struct RoomSwitcherProps {
+    /// The room that is currently shown.
+    pub current: String,
+    /// Called with the name of a room (or `@username` for a direct conversation) when the user switches to it.
+    pub on_select: Callback<String>,
+    /// The users we've had direct conversations with.
+    pub conversations: Vec<String>,
+}
+
+/// Lists the rooms on the server, and lets the user switch between them or create new ones.
+#[function_component]
+pub fn RoomSwitcher(props: &RoomSwitcherProps) -> Html {
+    let loc = use_location();
+    let new_room = use_state(|| String::new());
+    let new_conversation = use_state(|| String::new());
+
+    let rooms = {
+        let origin = loc.origin.clone();
+        use_async_with_options(
+            async move {
+                let response = reqwest::get(format!("{origin}/rooms"))
+                    .await
+                    .map_err(|why| format!("Error fetching rooms: {why}"))?;
+                response
+                    .json::<Vec<String>>()
+                    .await
+                    .map_err(|why| format!("Error reading rooms: {why}"))
+            },
+            UseAsyncOptions::enable_auto(),
+        )
+    };
+
+    let create_room = {
+        let origin = loc.origin.clone();
+        let new_room = new_room.clone();
+        let rooms = rooms.clone();
+        let on_select = props.on_select.clone();
+        use_async(async move {
+            let name = (*new_room).clone();
+            let client = reqwest::Client::builder()
+                .build()
+                .expect("Failed to build client");
+            let result = client
+                .post(format!("{origin}/rooms/{name}"))
+                .send()
+                .await;
+            match result {
+                Err(why) => Err(format!("Error sending room creation request: {why}")),
+                Ok(res) => {
+                    if res.status() == StatusCode::CREATED {
+                        new_room.set(String::new());
+                        rooms.run();
+                        on_select.emit(name);
+                        Ok(())
+                    } else {
+                        let why = res
+                            .text()
+                            .await
+                            .unwrap_or("server returned non-text data".to_string());
+                        Err(format!("Error creating room: {why}"))
+                    }
+                }
+            }
+        })
+    };
+
+    let new_room_cb = {
+        let new_room = new_room.clone();
+        Callback::from(move |e: InputEvent| {
+            let event: Event = e.dyn_into().unwrap_throw();
+            let event_target = event.target().unwrap_throw();
+            let target: HtmlInputElement = event_target.dyn_into().unwrap_throw();
+            new_room.set(target.value());
+        })
+    };
+    let new_conversation_cb = {
+        let new_conversation = new_conversation.clone();
+        Callback::from(move |e: InputEvent| {
+            let event: Event = e.dyn_into().unwrap_throw();
+            let event_target = event.target().unwrap_throw();
+            let target: HtmlInputElement = event_target.dyn_into().unwrap_throw();
+            new_conversation.set(target.value());
+        })
+    };
+    let start_conversation_cb = {
+        let new_conversation = new_conversation.clone();
+        let on_select = props.on_select.clone();
+        Callback::from(move |e: SubmitEvent| {
+            on_select.emit(format!("@{}", *new_conversation));
+            new_conversation.set(String::new());
+            e.prevent_default();
+        })
+    };
+    let create_room_cb = {
+        let create_room = create_room.clone();
+        Callback::from(move |e: SubmitEvent| {
+            create_room.run();
+            e.prevent_default();
+        })
+    };
+
+    html! {
+        <div>
+            <span>{"Rooms: "}</span>
+            {
+                for rooms.data.iter().flatten().map(|room| {
+                    let onclick = {
+                        let room = room.clone();
+                        let on_select = props.on_select.clone();
+                        Callback::from(move |_| on_select.emit(room.clone()))
+                    };
+                    html! {
+                        <button {onclick} disabled={*room == props.current}>{format!("#{room}")}</button>
+                    }
+                })
+            }
+            <form onsubmit={create_room_cb}>
+                <input type="text" placeholder="New room name" value={(*new_room).clone()} oninput={new_room_cb} />
+                <input type="submit" value="Create room" disabled={create_room.loading || new_room.is_empty()} />
+            </form>
+            <span>{"Direct messages: "}</span>
+            {
+                for props.conversations.iter().map(|peer| {
+                    let view = format!("@{peer}");
+                    let onclick = {
+                        let view = view.clone();
+                        let on_select = props.on_select.clone();
+                        Callback::from(move |_| on_select.emit(view.clone()))
+                    };
+                    html! {
+                        <button {onclick} disabled={view == props.current}>{peer}</button>
+                    }
+                })
+            }
+            <form onsubmit={start_conversation_cb}>
+                <input type="text" placeholder="Username" value={(*new_conversation).clone()} oninput={new_conversation_cb} />
+                <input type="submit" value="Message user" disabled={new_conversation.is_empty()} />
+            </form>
+            {
+                for rooms.error.iter().chain(create_room.error.iter()).map(|error| html! {
+                    <p style="text-color: red;">{error}</p>
+                })
+            }
+        </div>
+    }
+}
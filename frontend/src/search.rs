@@ -0,0 +1,149 @@
+use common::{ChatMessage, SearchResult, HIGHLIGHT_END, HIGHLIGHT_START};
+use wasm_bindgen::{JsCast, UnwrapThrowExt};
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_hooks::prelude::*;
+
+use crate::chat_window::Timestamp;
+
+/// Make a callback that puts the value of an input field into a state handle.
+fn bind_input(state: &UseStateHandle<String>) -> Callback<InputEvent> {
+    let state = state.clone();
+    Callback::from(move |e: InputEvent| {
+        let event: Event = e.dyn_into().unwrap_throw();
+        let event_target = event.target().unwrap_throw();
+        let target: HtmlInputElement = event_target.dyn_into().unwrap_throw();
+        state.set(target.value());
+    })
+}
+
+/// Convert the value of a date input (`YYYY-MM-DD`) into milliseconds since the Unix epoch.
+fn parse_date(value: &str) -> Option<i64> {
+    if value.is_empty() {
+        return None;
+    }
+    let millis = js_sys::Date::parse(value);
+    if millis.is_nan() {
+        None
+    } else {
+        Some(millis as i64)
+    }
+}
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+#[function_component]
+pub fn SearchBox() -> Html {
+    let loc = use_location();
+    let text = use_state(|| String::new());
+    let username = use_state(|| String::new());
+    let from_date = use_state(|| String::new());
+    let to_date = use_state(|| String::new());
+
+    let search = {
+        let text = text.clone();
+        let username = username.clone();
+        let from_date = from_date.clone();
+        let to_date = to_date.clone();
+        use_async(async move {
+            let mut params = vec![("q", (*text).clone())];
+            if !username.is_empty() {
+                params.push(("username", (*username).clone()));
+            }
+            if let Some(from) = parse_date(&from_date) {
+                params.push(("from", from.to_string()));
+            }
+            // The end date is inclusive, so search until the start of the next day.
+            if let Some(to) = parse_date(&to_date) {
+                params.push(("to", (to + DAY_MILLIS).to_string()));
+            }
+            let client = reqwest::Client::builder()
+                .build()
+                .expect("Failed to build client");
+            let response = client
+                .get(format!("{}/search", loc.origin))
+                .query(&params)
+                .send()
+                .await
+                .map_err(|why| format!("Error sending search request: {why}"))?;
+            if !response.status().is_success() {
+                let why = response
+                    .text()
+                    .await
+                    .unwrap_or("server returned non-text data".to_string());
+                return Err(format!("Error searching: {why}"));
+            }
+            response
+                .json::<Vec<SearchResult>>()
+                .await
+                .map_err(|why| format!("Error reading search results: {why}"))
+        })
+    };
+
+    let onsubmit = {
+        let search = search.clone();
+        Callback::from(move |e: SubmitEvent| {
+            search.run();
+            e.prevent_default();
+        })
+    };
+
+    html! {
+        <div>
+            <form {onsubmit}>
+                <input type="search" placeholder="Search messages" value={(*text).clone()} oninput={bind_input(&text)} />
+                <input type="text" placeholder="From user" value={(*username).clone()} oninput={bind_input(&username)} />
+                <label>{"From "}<input type="date" value={(*from_date).clone()} oninput={bind_input(&from_date)} /></label>
+                <label>{" to "}<input type="date" value={(*to_date).clone()} oninput={bind_input(&to_date)} /></label>
+                <input type="submit" value="Search" disabled={search.loading || text.trim().is_empty()} />
+            </form>
+            {
+                if let Some(error) = &search.error {
+                    html! { <p style="text-color: red;">{error}</p> }
+                } else {
+                    html! {}
+                }
+            }
+            {
+                match &search.data {
+                    Some(results) if results.is_empty() => html! { <p>{"No messages found"}</p> },
+                    Some(results) => html! {
+                        <ul>
+                            { for results.iter().map(|result| html! { <SearchResultDisplay result={result.clone()} /> }) }
+                        </ul>
+                    },
+                    None => html! {},
+                }
+            }
+        </div>
+    }
+}
+
+#[derive(Properties, PartialEq, Clone)]
+struct SearchResultDisplayProps {
+    pub result: SearchResult,
+}
+
+#[function_component]
+fn SearchResultDisplay(props: &SearchResultDisplayProps) -> Html {
+    let sender = match &props.result.message.message {
+        ChatMessage::TextMessage { room, username, .. } => format!("#{room} {username}"),
+        _ => String::new(),
+    };
+    // Split the snippet at the highlight markers: every odd-numbered part is a match.
+    let parts = props
+        .result
+        .snippet
+        .split(|c| c == HIGHLIGHT_START || c == HIGHLIGHT_END)
+        .enumerate()
+        .map(|(index, part)| {
+            if index % 2 == 1 {
+                html! { <mark>{part.to_string()}</mark> }
+            } else {
+                html! { {part.to_string()} }
+            }
+        });
+    html! {
+        <li><Timestamp millis={props.result.message.timestamp} /><span style="text-color: blue;">{sender}</span>{": "}{ for parts }</li>
+    }
+}
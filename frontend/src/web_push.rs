@@ -1,13 +1,16 @@
+use common::{NotificationLevel, NotificationPreferences, QuietHours};
+use k256::SecretKey;
 use wasm_bindgen::prelude::wasm_bindgen;
-use wasm_bindgen::{prelude::Closure};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
 use wasm_bindgen::{UnwrapThrowExt};
-use web_sys::{Notification, NotificationPermission, NotificationOptions};
+use web_sys::{HtmlInputElement, Notification, NotificationPermission, NotificationOptions};
 use yew::prelude::*;
+use yew_hooks::prelude::*;
 
 #[wasm_bindgen]
 extern "C" {
     fn get_subscription() -> bool;
-    fn resubscribe();
+    fn resubscribe(authenticate: JsValue);
 }
 
 #[function_component]
@@ -33,10 +36,23 @@ pub fn WebPushSetup() -> Html {
         // The notification only needs to be created in order to be shown
     }, ());
 
-    let resubscribe_cb = use_callback(|_,_| {
+    let username = use_local_storage::<String>("username".to_string());
+    let privkey = use_local_storage::<String>("private_key".to_string());
+    let resubscribe_cb = use_callback(|_, (username, privkey)| {
+        // Sign the new subscription's endpoint, so that the server knows which user it belongs to.
+        let username = (**username).clone().expect_throw("no username while setting up push?!");
+        let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+            .expect_throw("invalid stored jwk key");
+        let authenticate = Closure::once_into_js(move |endpoint: String| -> String {
+            let signature = common::signing::sign(
+                &privkey,
+                &common::signing::push_subscription_payload(&username, &endpoint),
+            );
+            serde_json::json!({ "username": username, "signature": signature }).to_string()
+        });
         #[allow(unused_unsafe)]  // this unsafe is actually needed
-        unsafe { resubscribe(); }
-    }, ());
+        unsafe { resubscribe(authenticate); }
+    }, (username, privkey));
 
     html! {
         <div>
@@ -51,6 +67,7 @@ pub fn WebPushSetup() -> Html {
                             <button onclick={send_notification_cb}>{"Send an example notification"}</button>
                             <p>{"Web Push subscription appears to be active: "}{state}</p>
                             <button onclick={resubscribe_cb}>{"Refresh Web Push subscription (will send a notification if successful)"}</button>
+                            <NotificationSettings />
                         </>
                     )
                 } else {html!()}
@@ -58,3 +75,203 @@ pub fn WebPushSetup() -> Html {
         </div>
     }
 }
+
+/// Format minutes after midnight for a time input.
+fn format_time_of_day(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Parse the `HH:MM` value of a time input into minutes after midnight.
+fn parse_time_of_day(value: &str) -> Option<u16> {
+    let (hours, minutes) = value.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// The browser's current timezone, as minutes ahead of UTC.
+fn local_utc_offset() -> i16 {
+    // getTimezoneOffset is the other way around: minutes *behind* UTC.
+    -(js_sys::Date::new_0().get_timezone_offset() as i16)
+}
+
+/// Make a callback that changes the preferences according to the value of an input field.
+fn bind_preference(
+    preferences: &UseStateHandle<NotificationPreferences>,
+    change: impl Fn(&mut NotificationPreferences, &HtmlInputElement) + 'static,
+) -> Callback<Event> {
+    let preferences = preferences.clone();
+    Callback::from(move |e: Event| {
+        let target: HtmlInputElement = e.target().unwrap_throw().dyn_into().unwrap_throw();
+        let mut changed = (*preferences).clone();
+        change(&mut changed, &target);
+        preferences.set(changed);
+    })
+}
+
+/// Lets the user choose what they get push notifications about.
+#[function_component]
+fn NotificationSettings() -> Html {
+    let loc = use_location();
+    let username = use_local_storage::<String>("username".to_string());
+    let privkey = use_local_storage::<String>("private_key".to_string());
+    let preferences = use_state(NotificationPreferences::default);
+    // Muted rooms are edited as text, so that typing a comma doesn't get swallowed.
+    let muted_rooms = use_state(String::new);
+
+    let load = {
+        let origin = loc.origin.clone();
+        let username = username.clone();
+        let privkey = privkey.clone();
+        let preferences = preferences.clone();
+        let muted_rooms = muted_rooms.clone();
+        use_async_with_options(
+            async move {
+                let username = (*username).clone().expect_throw("no username while setting up push?!");
+                let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+                    .expect_throw("invalid stored jwk key");
+                // Only we may read our preferences, so prove who we are
+                let timestamp = js_sys::Date::now() as i64;
+                let signature = common::signing::sign(
+                    &privkey,
+                    &common::signing::read_notification_preferences_payload(&username, timestamp),
+                );
+                let loaded: NotificationPreferences = reqwest::Client::new()
+                    .get(format!("{origin}/notification/preferences"))
+                    .query(&[("username", username), ("timestamp", timestamp.to_string()), ("signature", signature)])
+                    .send()
+                    .await
+                    .map_err(|why| format!("Error fetching notification settings: {why}"))?
+                    .json()
+                    .await
+                    .map_err(|why| format!("Error reading notification settings: {why}"))?;
+                muted_rooms.set(loaded.muted_rooms.join(", "));
+                preferences.set(loaded);
+                Ok::<(), String>(())
+            },
+            UseAsyncOptions::enable_auto(),
+        )
+    };
+
+    let save = {
+        let origin = loc.origin.clone();
+        let username = username.clone();
+        let privkey = privkey.clone();
+        let preferences = preferences.clone();
+        let muted_rooms = muted_rooms.clone();
+        use_async(async move {
+            let username = (*username).clone().expect_throw("no username while setting up push?!");
+            let privkey = SecretKey::from_jwk_str(&*privkey.as_ref().expect_throw("jwk key not stored?"))
+                .expect_throw("invalid stored jwk key");
+            let mut preferences = (*preferences).clone();
+            preferences.muted_rooms = muted_rooms
+                .split(',')
+                .map(|room| room.trim().trim_start_matches('#').to_string())
+                .filter(|room| !room.is_empty())
+                .collect();
+            let timestamp = js_sys::Date::now() as i64;
+            let signature = common::signing::sign(
+                &privkey,
+                &common::signing::notification_preferences_payload(&username, &preferences, timestamp),
+            );
+            let response = reqwest::Client::new()
+                .post(format!("{origin}/notification/preferences"))
+                .json(&serde_json::json!({
+                    "username": username,
+                    "preferences": preferences,
+                    "timestamp": timestamp,
+                    "signature": signature,
+                }))
+                .send()
+                .await
+                .map_err(|why| format!("Error saving notification settings: {why}"))?;
+            if !response.status().is_success() {
+                let why = response
+                    .text()
+                    .await
+                    .unwrap_or("server returned non-text data".to_string());
+                return Err(format!("Error saving notification settings: {why}"));
+            }
+            Ok(())
+        })
+    };
+
+    let onsubmit = {
+        let save = save.clone();
+        Callback::from(move |e: SubmitEvent| {
+            save.run();
+            e.prevent_default();
+        })
+    };
+    let mentions_only_cb = bind_preference(&preferences, |preferences, target| {
+        preferences.level = if target.checked() { NotificationLevel::MentionsAndDirect } else { NotificationLevel::All };
+    });
+    let skip_while_connected_cb = bind_preference(&preferences, |preferences, target| {
+        preferences.skip_while_connected = target.checked();
+    });
+    let quiet_hours_cb = bind_preference(&preferences, |preferences, target| {
+        preferences.quiet_hours = target.checked().then(|| QuietHours { start: 22 * 60, end: 7 * 60, utc_offset: local_utc_offset() });
+    });
+    let quiet_start_cb = bind_preference(&preferences, |preferences, target| {
+        if let (Some(quiet_hours), Some(start)) = (&mut preferences.quiet_hours, parse_time_of_day(&target.value())) {
+            quiet_hours.start = start;
+            quiet_hours.utc_offset = local_utc_offset();
+        }
+    });
+    let quiet_end_cb = bind_preference(&preferences, |preferences, target| {
+        if let (Some(quiet_hours), Some(end)) = (&mut preferences.quiet_hours, parse_time_of_day(&target.value())) {
+            quiet_hours.end = end;
+            quiet_hours.utc_offset = local_utc_offset();
+        }
+    });
+    let muted_rooms_cb = {
+        let muted_rooms = muted_rooms.clone();
+        Callback::from(move |e: InputEvent| {
+            let target: HtmlInputElement = e.target().unwrap_throw().dyn_into().unwrap_throw();
+            muted_rooms.set(target.value());
+        })
+    };
+
+    html! {
+        <form {onsubmit}>
+            <p>{"Notification settings"}</p>
+            <label>
+                <input type="checkbox" checked={preferences.level == NotificationLevel::MentionsAndDirect} onchange={mentions_only_cb} />
+                {"Only notify me about mentions, replies and direct messages"}
+            </label>
+            <br />
+            <label>
+                {"Muted rooms (you'll still be notified when you're mentioned): "}
+                <input type="text" placeholder="random, offtopic" value={(*muted_rooms).clone()} oninput={muted_rooms_cb} />
+            </label>
+            <br />
+            <label>
+                <input type="checkbox" checked={preferences.quiet_hours.is_some()} onchange={quiet_hours_cb} />
+                {"Quiet hours"}
+            </label>
+            {
+                match preferences.quiet_hours {
+                    Some(quiet_hours) => html! {
+                        <>
+                            {" from "}<input type="time" value={format_time_of_day(quiet_hours.start)} onchange={quiet_start_cb} />
+                            {" to "}<input type="time" value={format_time_of_day(quiet_hours.end)} onchange={quiet_end_cb} />
+                        </>
+                    },
+                    None => html! {},
+                }
+            }
+            <br />
+            <label>
+                <input type="checkbox" checked={preferences.skip_while_connected} onchange={skip_while_connected_cb} />
+                {"Don't notify me while I have the chat open"}
+            </label>
+            <br />
+            <input type="submit" value="Save notification settings" disabled={load.loading || save.loading} />
+            {
+                match (&load.error, &save.error) {
+                    (Some(error), _) | (None, Some(error)) => html! { <p style="text-color: red;">{error}</p> },
+                    (None, None) => html! {},
+                }
+            }
+        </form>
+    }
+}
@@ -16,6 +16,33 @@ navigator.serviceWorker.ready
     });
 // The above should be completed by the time that the other methods get called
 
+// When a push notification is clicked while the app is open, the service worker sends us the link to its message.
+// Links that arrive before the app is ready to show them are kept until it is.
+window.pending_open_link = null;
+window.open_link_handler = null;
+
+navigator.serviceWorker.addEventListener('message', function(event) {
+    if (!event.data || event.data.type !== 'open-link') {
+        return;
+    }
+    const search = new URL(event.data.url, window.location.origin).search;
+    if (window.open_link_handler === null) {
+        window.pending_open_link = search;
+    } else {
+        window.open_link_handler(search);
+    }
+});
+
+function set_open_link_handler(handler) {
+    // The handler gets the query string of the link, like `?room=...&message=...`.
+    window.open_link_handler = handler;
+    if (handler !== null && window.pending_open_link !== null) {
+        const search = window.pending_open_link;
+        window.pending_open_link = null;
+        handler(search);
+    }
+}
+
 function get_subscription() {
     // Return whether there is a current active subscription.
     return !(window.pushmanager_subscription === null);
@@ -41,8 +68,10 @@ function urlBase64ToUint8Array(base64String) {
   }
 
 
-function resubscribe() {
+function resubscribe(authenticate) {
     // Add a subscription, and if there is an old subscription, remove it.
+    // `authenticate` is called with the new subscription's endpoint,
+    // and returns a JSON string with the username and signature that tie the subscription to this user.
     navigator.serviceWorker.ready
     .then(async function(registration) {
         // Get the server's public key
@@ -66,7 +95,7 @@ function resubscribe() {
                   'Content-type': 'application/json'
                 },
                 body: JSON.stringify(
-                  subscription.toJSON()
+                  Object.assign(subscription.toJSON(), JSON.parse(authenticate(subscription.endpoint)))
                 )
               });
           });